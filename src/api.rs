@@ -0,0 +1,82 @@
+use std::collections::HashMap;
+
+use chrono::DateTime;
+use serde::Serialize;
+
+use crate::markdown::TocEntry;
+use crate::{timestamp, HtmlOptions, LabBook, Note, NoteID, NoteMetadata, Result, Theme};
+
+/// A note in the list of all notes
+#[derive(Serialize)]
+struct Listing<'a> {
+    id: &'a NoteID<'static>,
+    metadata: &'a NoteMetadata,
+    /// When the note's file was last modified, in RFC 3339 format
+    modified: String,
+    /// Number of other notes this note links to
+    links: usize,
+    /// Number of other notes that link to this note
+    backlinks: usize,
+}
+
+/// A single note, with its body rendered to html
+#[derive(Serialize)]
+struct Details<'a> {
+    #[serde(flatten)]
+    note: &'a Note<'a>,
+    outline: Vec<TocEntry>,
+    modified: Option<String>,
+    html: String,
+}
+
+impl LabBook {
+    /// List every note that can be loaded as JSON, in order of ID, with its
+    /// metadata, when it was last modified, and how many links it has to
+    /// and from other notes.
+    pub fn render_notes_json(&self) -> String {
+        let mut notes = self.recent();
+        notes.sort_by(|(a, _), (b, _)| a.id.0.cmp(&b.id.0));
+        let links: Vec<Vec<NoteID<'static>>> = notes.iter().map(|(note, _)| note.links()).collect();
+        let mut backlinks: HashMap<&str, usize> = HashMap::new();
+        for ((note, _), links) in notes.iter().zip(&links) {
+            for link in links.iter().filter(|link| link.0 != note.id.0) {
+                *backlinks.entry(&link.0).or_default() += 1;
+            }
+        }
+
+        let listings: Vec<Listing> = notes
+            .iter()
+            .zip(&links)
+            .map(|((note, modified), links)| Listing {
+                id: &note.id,
+                metadata: &note.header,
+                modified: timestamp(modified),
+                links: links.len(),
+                backlinks: backlinks.get(&*note.id.0).copied().unwrap_or(0),
+            })
+            .collect();
+        serde_json::to_string(&listings).unwrap()
+    }
+
+    /// A note as JSON: its ID, metadata, markdown source, outline, the notes
+    /// it links to, when it was last modified, and its body rendered to html
+    /// with the given theme and options.
+    pub fn render_note_json(
+        &self,
+        id: NoteID,
+        theme: &Theme,
+        opts: &HtmlOptions,
+    ) -> Result<String> {
+        let path = self.path(&id);
+        let note = self.note(id)?;
+        let modified = path.metadata().and_then(|metadata| metadata.modified());
+        let data_dir = opts.data_dir.as_deref().or(note.dir.as_deref());
+        let details = Details {
+            note: &note,
+            outline: note.body.outline(data_dir, Some(&note.id.0)),
+            modified: modified.ok().map(|time| timestamp(&DateTime::from(time))),
+            html: note.render_body_html(theme, opts).0.into_string(),
+        };
+        Ok(serde_json::to_string(&details).unwrap())
+    }
+}
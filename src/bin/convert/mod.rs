@@ -0,0 +1,104 @@
+//! Reading notes and writing their output, shared by the command line
+//! converters `lab2tex` and `lab2typ`
+
+use std::convert::TryFrom;
+use std::fs;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+
+use labnotes::{Note, NoteID};
+
+/// Use the file name as the ID of the note, if it is a valid ID
+pub fn note_id(path: &Path) -> NoteID<'static> {
+    path.file_stem()
+        .and_then(|stem| NoteID::try_from(stem.to_string_lossy().into_owned()).ok())
+        .unwrap_or_else(|| NoteID::try_from("index").unwrap())
+}
+
+/// Load the note in a markdown file, or read it from stdin if the path is
+/// `-`. If `strict`, front matter that can't be parsed is an error rather
+/// than a warning.
+pub fn load_note(input: &Path, strict: bool) -> Result<Note<'static>, String> {
+    let note = if input == Path::new("-") {
+        let mut source = String::new();
+        io::stdin()
+            .read_to_string(&mut source)
+            .map_err(|e| format!("Error reading stdin: {}", e))?;
+        let note = Note::parse(NoteID::try_from("index").unwrap(), source);
+        if strict {
+            note.check_metadata().map(|_| note)
+        } else {
+            Ok(note)
+        }
+    } else if strict {
+        Note::load_strict(note_id(input), input.to_path_buf())
+    } else {
+        Note::load(note_id(input), input.to_path_buf())
+    };
+    note.map_err(|e| format!("Error loading note: {}", e))
+}
+
+pub fn print_warnings(input: &Path, warnings: &[String]) {
+    for warning in warnings {
+        eprintln!("warning: {}: {}", input.display(), warning);
+    }
+}
+
+/// Write the output to a file, or to stdout if no file is given
+pub fn write_output(output: Option<&Path>, text: &str) -> Result<(), String> {
+    match output {
+        Some(path) => {
+            fs::write(path, text).map_err(|e| format!("Error writing {}: {}", path.display(), e))
+        }
+        None => {
+            print!("{}", text);
+            Ok(())
+        }
+    }
+}
+
+/// Convert every markdown file in a directory with `render`, writing each
+/// to a file with the same name and the given extension, and carrying on
+/// past any that fail. Notes are loaded as with [`load_note`].
+pub fn convert_dir(
+    dir: &Path,
+    output: Option<&Path>,
+    extension: &str,
+    strict: bool,
+    render: impl Fn(&Note) -> (String, Vec<String>),
+) -> Result<(), String> {
+    let output = output.unwrap_or(dir);
+    fs::create_dir_all(output)
+        .map_err(|e| format!("Error creating {}: {}", output.display(), e))?;
+    let mut inputs: Vec<PathBuf> = fs::read_dir(dir)
+        .map_err(|e| format!("Error reading {}: {}", dir.display(), e))?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.is_file() && path.extension().is_some_and(|ext| ext == "md"))
+        .collect();
+    inputs.sort();
+
+    let mut failed = 0;
+    for input in &inputs {
+        let result = load_note(input, strict).and_then(|note| {
+            let (text, warnings) = render(&note);
+            print_warnings(input, &warnings);
+            let mut filename = PathBuf::from(input.file_name().unwrap());
+            filename.set_extension(extension);
+            write_output(Some(&output.join(filename)), &text)
+        });
+        if let Err(e) = result {
+            eprintln!("{}", e);
+            failed += 1;
+        }
+    }
+
+    if failed == 0 {
+        Ok(())
+    } else {
+        Err(format!(
+            "{} of {} notes failed to convert",
+            failed,
+            inputs.len()
+        ))
+    }
+}
@@ -1,9 +1,12 @@
-use std::convert::TryFrom;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::process;
 
 use clap::Parser;
 
-use labnotes::{Note, NoteID};
+use labnotes::{LabBook, Note, TexOptions};
+
+mod convert;
+use convert::{convert_dir, load_note, note_id, print_warnings, write_output};
 
 #[derive(Debug, Parser)]
 #[command(
@@ -11,19 +14,121 @@ use labnotes::{Note, NoteID};
     about = "Converts a markdown file into a latex file."
 )]
 struct Args {
-    /// Markdown file to convert
+    /// Markdown file to convert, `-` to read from stdin, or a directory to
+    /// convert every markdown file in it
     input: PathBuf,
+
+    /// File to write the latex to, instead of stdout. When converting a
+    /// directory, the directory to write the latex files to (by default,
+    /// they are written next to the markdown files)
+    #[arg(short, long)]
+    output: Option<PathBuf>,
+
+    /// Leave out the preamble, so the output can be `\input` into another
+    /// document
+    #[arg(long)]
+    fragment: bool,
+
+    /// Use booktabs rules in tables
+    #[arg(long)]
+    booktabs: bool,
+
+    /// Typeset tables with longtable, so they can break across pages
+    #[arg(long)]
+    longtable: bool,
+
+    /// Cite arXiv and DOI references in a bibliography
+    #[arg(long)]
+    bibliography: bool,
+
+    /// Start with a table of contents
+    #[arg(long)]
+    toc: bool,
+
+    /// Compile the input and the notes it leads to into a single book
+    #[arg(long)]
+    book: bool,
+
+    /// Number of links to follow from the input when compiling a book
+    #[arg(long, default_value_t = 1, requires = "book")]
+    depth: usize,
+
+    /// Make each note of a book a section, rather than a chapter
+    #[arg(long, requires = "book")]
+    sections: bool,
+
+    /// Fail on front matter that can't be parsed, instead of ignoring it
+    /// with a warning
+    #[arg(long)]
+    strict: bool,
 }
 
 fn main() {
     let args = Args::parse();
-    match Note::load(NoteID::try_from("index").unwrap(), args.input) {
-        Ok(note) => {
-            let tex = note.render_tex();
-            print!("{}", tex);
-        }
-        Err(_) => {
-            eprintln!("Error loading note!");
+    let opts = TexOptions {
+        booktabs: args.booktabs,
+        longtable: args.longtable,
+        bibliography: args.bibliography,
+        fragment: args.fragment,
+        toc: args.toc,
+        diagram_dir: diagram_dir(&args),
+        diagram_path: None,
+        data_dir: None,
+        note_id: None,
+    };
+
+    let stdin = args.input == Path::new("-");
+    if args.book && (stdin || args.input.is_dir()) {
+        eprintln!("A book must start from a single markdown file!");
+        process::exit(2);
+    }
+
+    let result = if args.input.is_dir() {
+        convert_dir(
+            &args.input,
+            args.output.as_deref(),
+            "tex",
+            args.strict,
+            |note| note.render_tex_with(&opts),
+        )
+    } else if args.book {
+        let dir = args.input.parent().map(PathBuf::from).unwrap_or_default();
+        match LabBook::new(dir).book(note_id(&args.input), args.depth) {
+            Ok(notes) => {
+                let checked = notes.iter().filter(|_| args.strict).try_for_each(|note| {
+                    note.check_metadata()
+                        .map_err(|e| format!("Error loading {}: {}", note.id, e))
+                });
+                checked.and_then(|_| {
+                    let (tex, warnings) = Note::render_tex_book(&notes, &opts, !args.sections);
+                    print_warnings(&args.input, &warnings);
+                    write_output(args.output.as_deref(), &tex)
+                })
+            }
+            Err(e) => Err(format!("Error loading book: {}", e)),
         }
+    } else {
+        load_note(&args.input, args.strict).and_then(|note| {
+            let (tex, warnings) = note.render_tex_with(&opts);
+            print_warnings(&args.input, &warnings);
+            write_output(args.output.as_deref(), &tex)
+        })
+    };
+
+    if let Err(e) = result {
+        eprintln!("{}", e);
+        process::exit(1);
+    }
+}
+
+/// Graphviz diagrams and plots are rendered to a `diagrams` directory next
+/// to the latex, and included by their absolute path
+fn diagram_dir(args: &Args) -> Option<PathBuf> {
+    let base = match &args.output {
+        Some(output) if args.input.is_dir() => output.as_path(),
+        Some(output) => output.parent().unwrap_or(Path::new("")),
+        None if args.input.is_dir() => args.input.as_path(),
+        None => Path::new(""),
     };
+    std::path::absolute(base.join("diagrams")).ok()
 }
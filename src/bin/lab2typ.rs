@@ -0,0 +1,66 @@
+use std::path::PathBuf;
+use std::process;
+
+use clap::Parser;
+
+use labnotes::TypstOptions;
+
+mod convert;
+use convert::{convert_dir, load_note, print_warnings, write_output};
+
+#[derive(Debug, Parser)]
+#[command(
+    name = "lab2typ",
+    about = "Converts a markdown file into a typst file."
+)]
+struct Args {
+    /// Markdown file to convert, `-` to read from stdin, or a directory to
+    /// convert every markdown file in it
+    input: PathBuf,
+
+    /// File to write the typst to, instead of stdout. When converting a
+    /// directory, the directory to write the typst files to (by default,
+    /// they are written next to the markdown files)
+    #[arg(short, long)]
+    output: Option<PathBuf>,
+
+    /// Leave out the `#set` rules at the start, so the output can be
+    /// `#include`d into another document
+    #[arg(long)]
+    fragment: bool,
+
+    /// Fail on front matter that can't be parsed, instead of ignoring it
+    /// with a warning
+    #[arg(long)]
+    strict: bool,
+}
+
+fn main() {
+    let args = Args::parse();
+    let opts = TypstOptions {
+        fragment: args.fragment,
+        data_dir: None,
+        note_id: None,
+    };
+
+    let result = if args.input.is_dir() {
+        convert_dir(
+            &args.input,
+            args.output.as_deref(),
+            "typ",
+            args.strict,
+            |note| note.render_typst_with(&opts),
+        )
+    } else {
+        load_note(&args.input, args.strict).and_then(|note| {
+            let (typst, warnings) = note.render_typst_with(&opts);
+            print_warnings(&args.input, &warnings);
+            write_output(args.output.as_deref(), &typst)
+        })
+    };
+
+    if let Err(e) = result {
+        eprintln!("{}", e);
+        process::exit(1);
+    }
+}
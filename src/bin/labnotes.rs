@@ -1,27 +1,245 @@
+use std::convert::TryFrom;
+use std::env;
+use std::panic;
 use std::path::PathBuf;
+use std::process;
 
-use clap::Parser;
+use chrono::Datelike;
+use clap::{Parser, Subcommand};
 use maud::Markup;
-use rocket::{fs::FileServer, get, launch, routes, State};
+use rocket::http::ContentType;
+use rocket::request::{FromParam, Request};
+use rocket::response::{self, Redirect, Responder};
+use rocket::tokio::task;
+use rocket::{fs::FileServer, get, routes, Build, Rocket, State};
 
-use labnotes::{LabBook, NoteID, Theme};
+use labnotes::{Error, HtmlOptions, Journal, LabBook, MathOutput, Note, NoteID, Theme};
+
+/// Run blocking work, such as rendering a note (which may read data or run
+/// Graphviz), on a thread where it won't hold up other requests
+async fn blocking<T: Send + 'static>(work: impl FnOnce() -> T + Send + 'static) -> T {
+    task::spawn_blocking(work)
+        .await
+        .unwrap_or_else(|e| panic::resume_unwind(e.into_panic()))
+}
 
 #[get("/", format = "html")]
-fn index(book: &State<LabBook>, theme: &State<Theme>) -> Option<Markup> {
-    book.index().ok().map(|note| note.render_html(&theme))
+async fn index(
+    book: &State<LabBook>,
+    theme: &State<Theme>,
+    opts: &State<HtmlOptions>,
+) -> Result<Markup, Error> {
+    let (book, theme, opts) = (
+        book.inner().clone(),
+        theme.inner().clone(),
+        opts.inner().clone(),
+    );
+    blocking(move || Ok(book.index()?.render_html_with(&theme, &opts))).await
+}
+
+#[get("/<id>", format = "html", rank = 2)]
+async fn note(
+    id: NoteID<'_>,
+    book: &State<LabBook>,
+    theme: &State<Theme>,
+    opts: &State<HtmlOptions>,
+) -> Result<Markup, Error> {
+    let id = id.into_owned();
+    let (book, theme, opts) = (
+        book.inner().clone(),
+        theme.inner().clone(),
+        opts.inner().clone(),
+    );
+    blocking(move || Ok(book.note(id)?.render_html_with(&theme, &opts))).await
+}
+
+/// A one-segment path that isn't a valid note ID, once no other route (such
+/// as a file server) has matched it
+#[get("/<id>", format = "html", rank = 20)]
+fn invalid_note(id: &str) -> Option<Error> {
+    NoteID::try_from(id).err()
+}
+
+/// Formats a note can be downloaded in, from `/{id}.{extension}`
+enum Download {
+    /// The markdown source
+    Markdown,
+    /// The note rendered to latex
+    Tex,
+    /// A zip of the latex with the images and diagrams it includes
+    Zip,
+}
+
+impl Download {
+    /// The note in this format, with its content type
+    fn render(self, note: Note) -> Result<(ContentType, Vec<u8>), Error> {
+        Ok(match self {
+            Download::Markdown => (
+                ContentType::new("text", "markdown").with_params(("charset", "utf-8")),
+                note.body.0.into_bytes(),
+            ),
+            Download::Tex => (
+                ContentType::new("application", "x-tex").with_params(("charset", "utf-8")),
+                note.render_tex().into_bytes(),
+            ),
+            Download::Zip => (ContentType::ZIP, note.render_tex_bundle()?),
+        })
+    }
+}
+
+/// File name of a note to download, e.g. `note.tex`
+struct DownloadFile<'a> {
+    id: NoteID<'a>,
+    format: Download,
+}
+
+impl<'a> FromParam<'a> for DownloadFile<'a> {
+    type Error = &'a str;
+
+    fn from_param(param: &'a str) -> Result<Self, Self::Error> {
+        let (id, extension) = param.rsplit_once('.').ok_or(param)?;
+        let format = match extension {
+            "md" => Download::Markdown,
+            "tex" => Download::Tex,
+            "zip" => Download::Zip,
+            _ => return Err(param),
+        };
+        let id = NoteID::try_from(id).map_err(|_| param)?;
+        Ok(DownloadFile { id, format })
+    }
+}
+
+#[get("/<file>")]
+async fn download(
+    file: DownloadFile<'_>,
+    book: &State<LabBook>,
+) -> Result<(ContentType, Vec<u8>), Error> {
+    let (id, format) = (file.id.into_owned(), file.format);
+    let book = book.inner().clone();
+    blocking(move || book.note(id).and_then(|note| format.render(note))).await
+}
+
+/// URL the notebook is served at, which the feed links to
+struct BaseUrl(String);
+
+#[get("/feed.atom?<tag>")]
+async fn feed(
+    tag: Option<String>,
+    base_url: &State<BaseUrl>,
+    book: &State<LabBook>,
+) -> (ContentType, String) {
+    let atom = ContentType::new("application", "atom+xml");
+    let (base_url, book) = (base_url.0.clone(), book.inner().clone());
+    let feed = blocking(move || book.render_atom(&base_url, tag.as_deref())).await;
+    (atom, feed)
+}
+
+#[get("/api/notes")]
+async fn api_notes(book: &State<LabBook>) -> (ContentType, String) {
+    let book = book.inner().clone();
+    (
+        ContentType::JSON,
+        blocking(move || book.render_notes_json()).await,
+    )
+}
+
+/// An error from the API, which responds with the status and message as
+/// JSON rather than with an error page
+struct ApiError(Error);
+
+impl<'r> Responder<'r, 'static> for ApiError {
+    fn respond_to(self, request: &'r Request<'_>) -> response::Result<'static> {
+        let status = self.0.status();
+        if status.class().is_server_error() {
+            rocket::error!("{}", self.0);
+        }
+        let body = serde_json::json!({
+            "status": status.code,
+            "error": self.0.message(),
+        });
+        (status, (ContentType::JSON, body.to_string())).respond_to(request)
+    }
 }
 
-#[get("/<id>", format = "html")]
-fn note<'a>(id: NoteID<'a>, book: &State<LabBook>, theme: &State<Theme>) -> Option<Markup> {
-    book.note(id).ok().map(|note| note.render_html(&theme))
+impl From<Error> for ApiError {
+    fn from(error: Error) -> Self {
+        ApiError(error)
+    }
+}
+
+#[get("/api/notes/<id>")]
+async fn api_note(
+    id: Result<NoteID<'_>, Error>,
+    book: &State<LabBook>,
+    theme: &State<Theme>,
+    opts: &State<HtmlOptions>,
+) -> Result<(ContentType, String), ApiError> {
+    let id = id?.into_owned();
+    let (book, theme, opts) = (
+        book.inner().clone(),
+        theme.inner().clone(),
+        opts.inner().clone(),
+    );
+    let json = blocking(move || book.render_note_json(id, &theme, &opts)).await?;
+    Ok((ContentType::JSON, json))
+}
+
+#[get("/recent?<days>", format = "html")]
+async fn recent(days: Option<u64>, book: &State<LabBook>, theme: &State<Theme>) -> Markup {
+    let (book, theme) = (book.inner().clone(), theme.inner().clone());
+    blocking(move || book.render_recent(days.unwrap_or(7), &theme)).await
+}
+
+#[get("/journal", format = "html")]
+fn journal(journal: &State<Journal>, theme: &State<Theme>) -> Option<Markup> {
+    let today = chrono::Local::now().date_naive();
+    journal.render_calendar(today.year(), today.month(), theme)
+}
+
+#[get("/journal/<year>/<month>", format = "html")]
+fn calendar(
+    year: i32,
+    month: u32,
+    journal: &State<Journal>,
+    theme: &State<Theme>,
+) -> Option<Markup> {
+    journal.render_calendar(year, month, theme)
+}
+
+#[get("/journal/today")]
+async fn journal_today(journal: &State<Journal>) -> Result<Redirect, Error> {
+    let journal = journal.inner().clone();
+    let id = blocking(move || journal.today(&default_author())).await?;
+    Ok(Redirect::to(format!("/journal/{}", id)))
+}
+
+#[get("/journal/<id>", format = "html")]
+async fn journal_entry(
+    id: Result<NoteID<'_>, Error>,
+    journal: &State<Journal>,
+    theme: &State<Theme>,
+    opts: &State<HtmlOptions>,
+) -> Result<Markup, Error> {
+    let id = id?.into_owned();
+    let journal = journal.inner().clone();
+    let (theme, opts) = (theme.inner().clone(), opts.inner().clone());
+    blocking(move || {
+        let entry = journal.entry(id)?;
+        Ok(journal.render_entry(&entry, &theme, &opts))
+    })
+    .await
 }
 
 #[derive(Parser, Debug)]
 #[command(
     name = "labnotes",
-    about = "Serves a directory of markdown files as a simple website."
+    about = "Serves a directory of markdown files as a simple website.",
+    args_conflicts_with_subcommands = true
 )]
 struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+
     /// Directory that contains the markdown files
     #[structopt(default_value = ".")]
     dir: PathBuf,
@@ -29,16 +247,131 @@ struct Args {
     /// Use light theme instead of dark theme
     #[structopt(long)]
     light: bool,
+
+    /// Show a table of contents of each note in a sidebar
+    #[arg(long)]
+    toc: bool,
+
+    /// How to output math: htmlAndMathml, html, or mathml (which
+    /// displays without the KaTeX stylesheet)
+    #[arg(long, default_value_t = MathOutput::HtmlAndMathml)]
+    math: MathOutput,
+
+    /// Pattern naming journal entries in `{dir}/journal` after their
+    /// date, using `strftime` syntax
+    #[arg(long, default_value = "%Y-%m-%d")]
+    journal_pattern: String,
+
+    /// URL the notebook is served at, used for links in the Atom feed,
+    /// e.g. `https://notes.example.com` (by default, the address and port
+    /// the server listens on)
+    #[arg(long)]
+    base_url: Option<String>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Create a new note from a template
+    New {
+        /// ID of the new note. If not given, an ID is made from the
+        /// current time, e.g. `20240131154502`
+        id: Option<String>,
+
+        /// Directory that contains the markdown files
+        #[arg(short, long, default_value = ".")]
+        dir: PathBuf,
+
+        /// Template to fill in, from `{dir}/templates/{template}.md`
+        #[arg(short, long, default_value = "default")]
+        template: String,
+
+        /// Author to fill in (by default, the current user)
+        #[arg(short, long)]
+        author: Option<String>,
+    },
 }
 
-#[launch]
-fn rocket() -> _ {
+#[rocket::main]
+async fn main() {
     let args = Args::parse();
+    match args.command {
+        Some(Command::New {
+            id,
+            dir,
+            template,
+            author,
+        }) => {
+            let id = match id {
+                Some(id) => NoteID::try_from(id).unwrap_or_else(|e| {
+                    eprintln!("Invalid note ID: {}", e);
+                    process::exit(2);
+                }),
+                None => NoteID::timestamp(),
+            };
+            let author = author.unwrap_or_else(default_author);
+            match LabBook::new(dir).create(&id, &template, &author) {
+                Ok(path) => println!("{}", path.display()),
+                Err(e) => {
+                    eprintln!("Error creating note: {}", e);
+                    process::exit(1);
+                }
+            }
+        }
+        None => {
+            if let Err(e) = rocket(args).launch().await {
+                eprintln!("{}", e);
+                process::exit(1);
+            }
+        }
+    }
+}
+
+/// Name of the current user, to use as the author of new notes
+fn default_author() -> String {
+    env::var("USER")
+        .or_else(|_| env::var("USERNAME"))
+        .unwrap_or_default()
+}
+
+fn rocket(args: Args) -> Rocket<Build> {
     let staticdir = args.dir.join("static");
-    let rocket = rocket::build()
-        .mount("/", routes![index, note])
+    let journal = Journal::new(args.dir.clone(), &args.journal_pattern).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        process::exit(2);
+    });
+    let rocket = rocket::build();
+    let base_url = args.base_url.unwrap_or_else(|| {
+        let config = rocket::Config::from(rocket.figment());
+        format!("http://{}:{}", config.address, config.port)
+    });
+    let rocket = rocket
+        .mount(
+            "/",
+            routes![
+                index,
+                note,
+                invalid_note,
+                download,
+                feed,
+                recent,
+                api_notes,
+                api_note,
+                journal,
+                calendar,
+                journal_today,
+                journal_entry
+            ],
+        )
         .manage(LabBook::new(args.dir))
-        .manage(Theme::new(args.light));
+        .manage(journal)
+        .manage(BaseUrl(base_url))
+        .manage(Theme::new(args.light))
+        .manage(HtmlOptions {
+            math: args.math,
+            toc: args.toc,
+            data_dir: None,
+            note_id: None,
+        });
 
     if staticdir.exists() {
         rocket.mount("/static", FileServer::from(staticdir))
@@ -0,0 +1,92 @@
+use std::env;
+use std::fs::{self, read_dir};
+use std::io::{Cursor, Write};
+use std::path::{Component, Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use zip::write::SimpleFileOptions;
+use zip::ZipWriter;
+
+use crate::{Error, Note, Result, TexOptions};
+
+/// Whether an image is a file inside the note's directory, which can be
+/// copied into the bundle at the same relative path
+fn is_local(dest: &str) -> bool {
+    let path = Path::new(dest);
+    !dest.contains("://")
+        && path
+            .components()
+            .all(|component| matches!(component, Component::Normal(_) | Component::CurDir))
+}
+
+/// Add the contents of a file to a zip archive
+fn add_file(zip: &mut ZipWriter<Cursor<Vec<u8>>>, name: &str, contents: &[u8]) -> Result<()> {
+    let path = PathBuf::from(name);
+    zip.start_file(name, SimpleFileOptions::default())
+        .map_err(|e| Error::io(path.clone(), e.into()))?;
+    zip.write_all(contents).map_err(|e| Error::io(path, e))
+}
+
+impl Note<'_> {
+    /// Zip up the note as latex, ready to compile: a directory named after
+    /// the note, holding `{id}.tex` with a bibliography of its arXiv and DOI
+    /// references, the images it includes from the note's directory, and its
+    /// Graphviz diagrams and plots in `diagrams`.
+    pub fn render_tex_bundle(&self) -> Result<Vec<u8>> {
+        // Diagrams are rendered to a directory of their own, so that only
+        // those in this note are bundled
+        static BUNDLES: AtomicUsize = AtomicUsize::new(0);
+        let diagram_dir = env::temp_dir().join(format!(
+            "labnotes-{}-{}",
+            process::id(),
+            BUNDLES.fetch_add(1, Ordering::Relaxed)
+        ));
+        let opts = TexOptions {
+            bibliography: true,
+            diagram_dir: Some(diagram_dir.clone()),
+            diagram_path: Some(PathBuf::from("diagrams")),
+            ..TexOptions::default()
+        };
+        let (tex, _) = self.render_tex_with(&opts);
+
+        let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+        let written = self.write_bundle(&mut zip, &tex, &diagram_dir);
+        let _ = fs::remove_dir_all(&diagram_dir);
+        written?;
+
+        let path = PathBuf::from(format!("{}.zip", self.id));
+        let zip = zip.finish().map_err(|e| Error::io(path, e.into()))?;
+        Ok(zip.into_inner())
+    }
+
+    /// Add the latex, images and diagrams of the note to the bundle
+    fn write_bundle(
+        &self,
+        zip: &mut ZipWriter<Cursor<Vec<u8>>>,
+        tex: &str,
+        diagram_dir: &Path,
+    ) -> Result<()> {
+        add_file(zip, &format!("{0}/{0}.tex", self.id), tex.as_bytes())?;
+        let dir = self.dir.as_deref().unwrap_or(Path::new(""));
+        for image in self.body.images(self.dir.as_deref(), Some(&self.id.0)) {
+            if !is_local(&image) {
+                continue;
+            }
+            // Missing images are left for latex to report
+            if let Ok(contents) = fs::read(dir.join(&image)) {
+                let name = image.trim_start_matches("./");
+                add_file(zip, &format!("{}/{}", self.id, name), &contents)?;
+            }
+        }
+        for entry in read_dir(diagram_dir).into_iter().flatten() {
+            let path = entry
+                .map_err(|e| Error::io(diagram_dir.to_path_buf(), e))?
+                .path();
+            let name = path.file_name().unwrap_or_default().to_string_lossy();
+            let contents = fs::read(&path).map_err(|e| Error::io(path.clone(), e))?;
+            add_file(zip, &format!("{}/diagrams/{}", self.id, name), &contents)?;
+        }
+        Ok(())
+    }
+}
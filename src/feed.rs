@@ -0,0 +1,156 @@
+use std::fmt::Write;
+
+use chrono::{DateTime, Local, NaiveDate, TimeZone};
+use pulldown_cmark_escape::{escape_href, escape_html};
+
+use crate::{timestamp, HtmlOptions, LabBook, MathOutput, Note, Theme};
+
+/// Number of notes listed in the feed
+const FEED_LENGTH: usize = 20;
+
+/// Length of the summary of each note, in characters
+const SUMMARY_LENGTH: usize = 300;
+
+/// Date a note was written, from its metadata: either a date such as
+/// `2024-01-31`, or a date and time in RFC 3339 format
+fn published(note: &Note) -> Option<DateTime<Local>> {
+    let date = note.header.date.as_deref()?.trim();
+    if let Ok(time) = DateTime::parse_from_rfc3339(date) {
+        return Some(time.with_timezone(&Local));
+    }
+    let date = NaiveDate::parse_from_str(date, "%Y-%m-%d").ok()?;
+    Local
+        .from_local_datetime(&date.and_hms_opt(0, 0, 0)?)
+        .earliest()
+}
+
+fn escape(text: &str) -> String {
+    let mut escaped = String::new();
+    escape_html(&mut escaped, text).unwrap();
+    escaped
+}
+
+/// Percent-encode text for a URL query, leaving only unreserved characters
+fn encode_query(text: &str) -> String {
+    let mut encoded = String::new();
+    for byte in text.bytes() {
+        if byte.is_ascii_alphanumeric() || b"-._~".contains(&byte) {
+            encoded.push(byte as char);
+        } else {
+            write!(encoded, "%{:02X}", byte).unwrap();
+        }
+    }
+    encoded
+}
+
+fn href(url: &str) -> String {
+    let mut escaped = String::new();
+    escape_href(&mut escaped, url).unwrap();
+    escaped
+}
+
+impl LabBook {
+    /// Render an Atom feed of the most recently modified notes, or of those
+    /// filed under `tag`. Each entry is dated by when its file was last
+    /// modified, and published on the date in its metadata, if any. The
+    /// notes are linked to under `base_url`, e.g. `http://localhost:8000`.
+    pub fn render_atom(&self, base_url: &str, tag: Option<&str>) -> String {
+        let base_url = base_url.trim_end_matches('/');
+        // Notes are loaded newest first, only until the feed is full
+        let notes: Vec<(Note<'static>, DateTime<Local>)> = self
+            .modified()
+            .into_iter()
+            .filter_map(|(id, modified)| Some((self.note(id).ok()?, modified)))
+            .filter(|(note, _)| tag.is_none_or(|tag| note.header.tags.iter().any(|t| t == tag)))
+            .take(FEED_LENGTH)
+            .collect();
+
+        let notebook = self
+            .index()
+            .map_or("LabNotes".to_string(), |index| index.title());
+        let (title, feed_url) = match tag {
+            Some(tag) => (
+                format!("{}: notes tagged {}", notebook, tag),
+                format!("{}/feed.atom?tag={}", base_url, encode_query(tag)),
+            ),
+            None => (notebook.clone(), format!("{}/feed.atom", base_url)),
+        };
+        let updated = notes
+            .first()
+            .map_or_else(Local::now, |(_, modified)| *modified);
+
+        let mut atom = String::from("<?xml version=\"1.0\" encoding=\"utf-8\"?>\n");
+        atom.push_str("<feed xmlns=\"http://www.w3.org/2005/Atom\">\n");
+        writeln!(atom, "<title>{}</title>", escape(&title)).unwrap();
+        writeln!(atom, "<id>{}</id>", escape(&feed_url)).unwrap();
+        writeln!(atom, "<link rel=\"self\" href=\"{}\"/>", href(&feed_url)).unwrap();
+        writeln!(atom, "<link href=\"{}/\"/>", href(base_url)).unwrap();
+        writeln!(atom, "<updated>{}</updated>", timestamp(&updated)).unwrap();
+        writeln!(atom, "<author><name>{}</name></author>", escape(&notebook)).unwrap();
+        atom.push_str("<generator>LabNotes</generator>\n");
+
+        // Math is written as MathML, since feed readers won't load the
+        // KaTeX stylesheet
+        let opts = HtmlOptions {
+            math: MathOutput::Mathml,
+            ..HtmlOptions::default()
+        };
+        let theme = Theme::new(false);
+        for (note, modified) in &notes {
+            let url = format!("{}/{}", base_url, note.id);
+            let content = note.render_body_html(&theme, &opts).0.into_string();
+
+            atom.push_str("<entry>\n");
+            writeln!(atom, "<title>{}</title>", escape(&note.title())).unwrap();
+            writeln!(atom, "<id>{}</id>", escape(&url)).unwrap();
+            writeln!(atom, "<link href=\"{}\"/>", href(&url)).unwrap();
+            writeln!(atom, "<updated>{}</updated>", timestamp(modified)).unwrap();
+            if let Some(published) = published(note) {
+                writeln!(atom, "<published>{}</published>", timestamp(&published)).unwrap();
+            }
+            if let Some(author) = &note.header.author {
+                writeln!(atom, "<author><name>{}</name></author>", escape(author)).unwrap();
+            }
+            for tag in &note.header.tags {
+                writeln!(atom, "<category term=\"{}\"/>", escape(tag)).unwrap();
+            }
+            if let Some(summary) = note.body.summary(SUMMARY_LENGTH) {
+                writeln!(atom, "<summary>{}</summary>", escape(&summary)).unwrap();
+            }
+            writeln!(
+                atom,
+                "<content type=\"html\">{}</content>",
+                escape(&content)
+            )
+            .unwrap();
+            atom.push_str("</entry>\n");
+        }
+        atom.push_str("</feed>\n");
+        atom
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_dir::TestDir;
+
+    #[test]
+    fn encodes_the_tag_in_the_feed_url() {
+        assert_eq!(encode_query("a&b=c d/é"), "a%26b%3Dc%20d%2F%C3%A9");
+
+        let notebook = TestDir::new(&[
+            (
+                "tagged.md",
+                "---\ntags: [\"a&b c\"]\ndate: 2024-01-31\n---\n# Tagged\n\nBody\n",
+            ),
+            ("other.md", "# Other\n"),
+        ]);
+        let atom = LabBook::new(notebook.path().to_path_buf())
+            .render_atom("https://notes.example.com/", Some("a&b c"));
+        assert!(atom.contains("<id>https://notes.example.com/feed.atom?tag=a%26b%20c</id>"));
+        assert!(atom.contains("<link href=\"https://notes.example.com/tagged\"/>"));
+        assert!(atom.contains("<published>2024-01-31T"));
+        assert!(!atom.contains("/other"));
+    }
+}
@@ -0,0 +1,190 @@
+use std::convert::TryFrom;
+use std::fs::create_dir_all;
+use std::path::PathBuf;
+
+use chrono::format::{Item, StrftimeItems};
+use chrono::{Datelike, Days, Local, Months, NaiveDate};
+use maud::{html, Markup, DOCTYPE};
+
+use crate::{Error, HtmlOptions, LabBook, Note, NoteID, Result, Template, Theme};
+
+/// Daily notes, kept in the `journal` directory of a notebook and named
+/// after the date they were written
+#[derive(Clone)]
+pub struct Journal {
+    /// Directory of the notebook, which holds the templates
+    dir: PathBuf,
+    /// Notes in `{dir}/journal`
+    entries: LabBook,
+    /// `strftime`-style pattern that turns a date into a note ID
+    pattern: String,
+}
+
+impl Journal {
+    /// Journal for the notebook in `dir`, with entries named by the
+    /// `strftime`-style `pattern`, e.g. `%Y-%m-%d`
+    pub fn new(dir: PathBuf, pattern: &str) -> Result<Journal> {
+        let invalid = || Error::InvalidJournalPattern {
+            pattern: pattern.to_string(),
+        };
+        if StrftimeItems::new(pattern).any(|item| matches!(item, Item::Error)) {
+            return Err(invalid());
+        }
+        let journal = Journal {
+            entries: LabBook::new(dir.join("journal")),
+            dir,
+            pattern: pattern.to_string(),
+        };
+        // Each date must give an ID that can be turned back into the date
+        let today = Local::now().date_naive();
+        match journal.id(today) {
+            Ok(id) if journal.date(&id) == Some(today) => Ok(journal),
+            _ => Err(invalid()),
+        }
+    }
+
+    /// ID of the entry for a date
+    pub fn id(&self, date: NaiveDate) -> Result<NoteID<'static>> {
+        NoteID::try_from(date.format(&self.pattern).to_string())
+    }
+
+    /// Date of the entry with an ID, if the ID matches the pattern
+    pub fn date(&self, id: &NoteID) -> Option<NaiveDate> {
+        NaiveDate::parse_from_str(id.into(), &self.pattern).ok()
+    }
+
+    /// Dates of all of the entries, in order
+    pub fn dates(&self) -> Vec<NaiveDate> {
+        let mut dates: Vec<NaiveDate> = self
+            .entries
+            .ids()
+            .iter()
+            .filter_map(|id| self.date(id))
+            .collect();
+        dates.sort();
+        dates
+    }
+
+    /// Get the entry with a specific id from `{dir}/journal/{id}.md`, which
+    /// transcludes notes and includes data from `{dir}`
+    pub fn entry<'a>(&self, id: NoteID<'a>) -> Result<Note<'a>> {
+        let note = self.entries.note(id)?;
+        Ok(Note {
+            dir: Some(self.dir.clone()),
+            ..note
+        })
+    }
+
+    /// ID of today's entry, which is created if it doesn't exist yet from
+    /// `{dir}/templates/journal.md` (or the default template)
+    pub fn today(&self, author: &str) -> Result<NoteID<'static>> {
+        let id = self.id(Local::now().date_naive())?;
+        let template = match Template::load(&self.dir, "journal") {
+            Err(Error::NotFound { .. }) => Template::load(&self.dir, "default")?,
+            template => template?,
+        };
+        let journal_dir = self.dir.join("journal");
+        create_dir_all(&journal_dir).map_err(|e| Error::io(journal_dir, e))?;
+        match self.entries.create_from(&id, &template, author) {
+            Ok(_) | Err(Error::AlreadyExists { .. }) => Ok(id),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Render an entry to html, with links to the entries before and after
+    /// it and to its month in the calendar
+    pub fn render_entry(&self, note: &Note, theme: &Theme, opts: &HtmlOptions) -> Markup {
+        let date = self.date(&note.id);
+        let dates = self.dates();
+        let previous = date.and_then(|date| dates.iter().rev().find(|&&d| d < date));
+        let next = date.and_then(|date| dates.iter().find(|&&d| d > date));
+        let nav = html! {
+            @if let Some(previous) = previous {
+                a href={ "/journal/" (self.id_string(*previous)) } { "← " (previous) }
+                " | "
+            }
+            @if let Some(date) = date {
+                a href={ "/journal/" (date.year()) "/" (date.month()) } { "Calendar" }
+            } @else {
+                a href="/journal" { "Calendar" }
+            }
+            @if let Some(next) = next {
+                " | "
+                a href={ "/journal/" (self.id_string(*next)) } { (next) " →" }
+            }
+        };
+        note.render_page(theme, opts, Some(nav))
+    }
+
+    /// Render a calendar of a month, linking each day that has an entry.
+    /// Returns `None` if the month doesn't exist.
+    pub fn render_calendar(&self, year: i32, month: u32, theme: &Theme) -> Option<Markup> {
+        let first = NaiveDate::from_ymd_opt(year, month, 1)?;
+        let next = first.checked_add_months(Months::new(1))?;
+        let previous = first.checked_sub_months(Months::new(1))?;
+        let days = next.signed_duration_since(first).num_days() as u32;
+        let dates = self.dates();
+
+        // Weeks start on Monday, with blank days before the first
+        let offset = first.weekday().num_days_from_monday();
+        let cells: Vec<Option<NaiveDate>> = (0..offset)
+            .map(|_| None)
+            .chain((0..days).map(|day| first.checked_add_days(Days::new(day.into()))))
+            .collect();
+
+        Some(html! {
+            (DOCTYPE)
+            head {
+                title { (first.format("%B %Y")) }
+                (theme)
+            }
+            body {
+                h1 { (first.format("%B %Y")) }
+                nav {
+                    a href={ "/journal/" (previous.year()) "/" (previous.month()) } {
+                        "← " (previous.format("%B %Y"))
+                    }
+                    " | "
+                    a href="/journal/today" { "Today" }
+                    " | "
+                    a href={ "/journal/" (next.year()) "/" (next.month()) } {
+                        (next.format("%B %Y")) " →"
+                    }
+                }
+                table {
+                    thead {
+                        tr {
+                            @for day in ["Mon", "Tue", "Wed", "Thu", "Fri", "Sat", "Sun"] {
+                                th { (day) }
+                            }
+                        }
+                    }
+                    tbody {
+                        @for week in cells.chunks(7) {
+                            tr {
+                                @for cell in week {
+                                    td {
+                                        @if let Some(date) = cell {
+                                            @if dates.binary_search(date).is_ok() {
+                                                a href={ "/journal/" (self.id_string(*date)) } {
+                                                    (date.day())
+                                                }
+                                            } @else {
+                                                (date.day())
+                                            }
+                                        }
+                                    }
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+        })
+    }
+
+    /// ID of the entry for a date, as a string
+    fn id_string(&self, date: NaiveDate) -> String {
+        date.format(&self.pattern).to_string()
+    }
+}
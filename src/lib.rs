@@ -1,17 +1,37 @@
 #![feature(proc_macro_hygiene)]
 
 use std::borrow::Cow;
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::convert::TryFrom;
 use std::fmt::{self, Display, Formatter};
-use std::fs::read_to_string;
-use std::path::PathBuf;
+use std::fs::{read_dir, read_to_string, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
 
+use chrono::{DateTime, Local, SecondsFormat};
 use maud::{html, Markup, DOCTYPE};
-use rocket::request::FromParam;
-
+use rocket::http::Status;
+use rocket::request::{FromParam, Request};
+use rocket::response::{self, Responder, Response};
+use serde::ser::{SerializeStruct, Serializer};
+use serde::{Deserialize, Serialize};
+
+mod api;
+mod bundle;
+mod feed;
+mod journal;
 mod markdown;
-
-pub use markdown::Markdown;
+mod notebook;
+mod recent;
+mod template;
+#[cfg(test)]
+mod test_dir;
+
+pub use journal::Journal;
+pub use markdown::{
+    Diagnostic, HtmlOptions, Markdown, MathOutput, TexChapter, TexOptions, TypstOptions,
+};
+pub use template::Template;
 
 #[derive(Debug)]
 pub enum Error {
@@ -24,12 +44,210 @@ pub enum Error {
     InvalidCharacter,
 
     /// Note with given NoteID not found in LabBook
-    NotFound,
+    NotFound { path: PathBuf, source: io::Error },
+
+    /// Not allowed to read the note file
+    PermissionDenied { path: PathBuf, source: io::Error },
+
+    /// Note file does not contain valid UTF-8
+    InvalidUtf8 { path: PathBuf, source: io::Error },
+
+    /// Any other error reading or writing a file
+    Io { path: PathBuf, source: io::Error },
+
+    /// Jupyter notebook that could not be parsed
+    InvalidNotebook {
+        path: PathBuf,
+        source: serde_json::Error,
+    },
+
+    /// A new note would overwrite the existing note at the path
+    AlreadyExists { path: PathBuf },
+
+    /// Journal pattern does not turn dates into valid note IDs
+    InvalidJournalPattern { pattern: String },
+
+    /// Note has yaml front matter that could not be parsed, when loaded
+    /// strictly. The path is missing if the note was not loaded from a file.
+    InvalidMetadata {
+        path: Option<PathBuf>,
+        source: serde_yaml::Error,
+    },
+}
+
+impl Error {
+    /// Classify an error from reading or writing the file at `path`
+    fn io(path: PathBuf, source: io::Error) -> Error {
+        match source.kind() {
+            io::ErrorKind::NotFound => Error::NotFound { path, source },
+            io::ErrorKind::AlreadyExists => Error::AlreadyExists { path },
+            io::ErrorKind::PermissionDenied => Error::PermissionDenied { path, source },
+            io::ErrorKind::InvalidData => Error::InvalidUtf8 { path, source },
+            _ => Error::Io { path, source },
+        }
+    }
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            Error::InvalidLength => write!(
+                f,
+                "note ID must be between 1 and {} characters long",
+                MAX_SIZE
+            ),
+            Error::InvalidCharacter => write!(
+                f,
+                "note ID may only contain `a-z`, `A-Z`, `0-9`, `_`, and `-`"
+            ),
+            Error::NotFound { path, .. } => write!(f, "{} not found", path.display()),
+            Error::PermissionDenied { path, .. } => {
+                write!(f, "permission denied accessing {}", path.display())
+            }
+            Error::InvalidUtf8 { path, .. } => {
+                write!(f, "{} is not valid UTF-8", path.display())
+            }
+            Error::Io { path, source } => {
+                write!(f, "error accessing {}: {}", path.display(), source)
+            }
+            Error::InvalidNotebook { path, source } => {
+                write!(f, "invalid notebook {}: {}", path.display(), source)
+            }
+            Error::AlreadyExists { path } => write!(f, "{} already exists", path.display()),
+            Error::InvalidJournalPattern { pattern } => write!(
+                f,
+                "journal pattern `{}` does not turn dates into valid note IDs",
+                pattern
+            ),
+            Error::InvalidMetadata {
+                path: Some(path),
+                source,
+            } => write!(f, "invalid front matter in {}: {}", path.display(), source),
+            Error::InvalidMetadata { path: None, source } => {
+                write!(f, "invalid front matter: {}", source)
+            }
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::InvalidLength
+            | Error::InvalidCharacter
+            | Error::AlreadyExists { .. }
+            | Error::InvalidJournalPattern { .. } => None,
+            Error::NotFound { source, .. }
+            | Error::PermissionDenied { source, .. }
+            | Error::InvalidUtf8 { source, .. }
+            | Error::Io { source, .. } => Some(source),
+            Error::InvalidNotebook { source, .. } => Some(source),
+            Error::InvalidMetadata { source, .. } => Some(source),
+        }
+    }
+}
+
+impl Error {
+    /// The HTTP status to respond with when this error occurs in the server
+    pub fn status(&self) -> Status {
+        match self {
+            Error::InvalidLength | Error::InvalidCharacter => Status::BadRequest,
+            Error::NotFound { .. } => Status::NotFound,
+            Error::AlreadyExists { .. } => Status::Conflict,
+            _ => Status::InternalServerError,
+        }
+    }
+
+    /// ID of the note whose file the error is about, if there is one
+    fn note_id(&self) -> Option<String> {
+        match self {
+            Error::NotFound { path, .. }
+            | Error::PermissionDenied { path, .. }
+            | Error::InvalidUtf8 { path, .. }
+            | Error::Io { path, .. }
+            | Error::InvalidNotebook { path, .. }
+            | Error::AlreadyExists { path }
+            | Error::InvalidMetadata {
+                path: Some(path), ..
+            } => Some(path.file_stem()?.to_string_lossy().into_owned()),
+            Error::InvalidLength
+            | Error::InvalidCharacter
+            | Error::InvalidJournalPattern { .. }
+            | Error::InvalidMetadata { path: None, .. } => None,
+        }
+    }
+
+    /// Message to show a client of the server when this error occurs,
+    /// which names the note but doesn't reveal where notes are stored
+    pub fn message(&self) -> String {
+        match (self, self.note_id()) {
+            (Error::NotFound { .. }, Some(id)) => format!("There is no note with ID \"{}\".", id),
+            (Error::InvalidLength | Error::InvalidCharacter, _) => {
+                format!("Invalid note ID: {}.", self)
+            }
+            (Error::AlreadyExists { .. }, Some(id)) => {
+                format!("There is already a note with ID \"{}\".", id)
+            }
+            (_, Some(id)) => format!("The note \"{}\" could not be read.", id),
+            (_, None) => "The request could not be completed.".to_string(),
+        }
+    }
+}
+
+/// Responds with a themed error page, using the `LabBook` and `Theme`
+/// managed by the server (if any). For a missing note, the page suggests
+/// notes with similar IDs.
+impl<'r> Responder<'r, 'static> for Error {
+    fn respond_to(self, request: &'r Request<'_>) -> response::Result<'static> {
+        let status = self.status();
+        if status.class().is_server_error() {
+            rocket::error!("{}", self);
+        }
+        let message = self.message();
+        let suggestions = match &self {
+            Error::NotFound { path, .. } => {
+                let id = path.file_stem().unwrap_or_default().to_string_lossy();
+                request
+                    .rocket()
+                    .state::<LabBook>()
+                    .map(|book| book.similar(&id))
+                    .unwrap_or_default()
+            }
+            _ => Vec::new(),
+        };
+
+        let default_theme = Theme::new(false);
+        let theme = request.rocket().state::<Theme>().unwrap_or(&default_theme);
+        let page = html! {
+            (DOCTYPE)
+            head {
+                title { (status) }
+                (theme)
+            }
+            body {
+                h1 { (status) }
+                p { (message) }
+                @if !suggestions.is_empty() {
+                    p { "Did you mean:" }
+                    ul {
+                        @for id in &suggestions {
+                            li { a href={ "/" (id) } { (id) } }
+                        }
+                    }
+                }
+                p { a href="/" { "Back to the index" } }
+            }
+        };
+        Response::build_from(page.respond_to(request)?)
+            .status(status)
+            .ok()
+    }
 }
 
 pub type Result<T> = std::result::Result<T, Error>;
 
 /// A collection of notes, stored as markdown files in a single directory
+#[derive(Clone)]
 pub struct LabBook {
     dir: PathBuf,
 }
@@ -45,20 +263,189 @@ impl LabBook {
         self.note(NoteID::try_from("index").unwrap())
     }
 
-    /// Get page with specific id from `{dir}/{id}.md`
+    /// Get page with specific id from `{dir}/{id}.md`, or from the Jupyter
+    /// notebook `{dir}/{id}.ipynb` if there is no markdown file
     pub fn note<'a>(&self, id: NoteID<'a>) -> Result<Note<'a>> {
-        let filename: &str = (&id).into();
-        let mut filename = PathBuf::from(filename);
-        filename.set_extension("md");
-        let path = self.dir.join(filename);
+        let path = self.path(&id);
         Note::load(id, path)
     }
+
+    /// Path of the file holding the note with a specific id: `{dir}/{id}.md`,
+    /// unless there is only a notebook `{dir}/{id}.ipynb`
+    fn path(&self, id: &NoteID) -> PathBuf {
+        let filename: &str = id.into();
+        let path = self.dir.join(filename);
+        let notebook = path.with_extension("ipynb");
+        let markdown = path.with_extension("md");
+        if !markdown.exists() && notebook.exists() {
+            notebook
+        } else {
+            markdown
+        }
+    }
+
+    /// Create a new note `{dir}/{id}.md` from the template
+    /// `{dir}/templates/{template}.md`, refusing to overwrite an existing
+    /// note. Returns the path of the new note.
+    ///
+    /// The template's `{{id}}`, `{{date}}`, `{{time}}` and `{{author}}`
+    /// placeholders are filled in, as is `{{number}}`, which counts up from
+    /// the largest `number` in the metadata of the existing notes.
+    pub fn create(&self, id: &NoteID, template: &str, author: &str) -> Result<PathBuf> {
+        self.create_from(id, &Template::load(&self.dir, template)?, author)
+    }
+
+    /// Create a new note `{dir}/{id}.md` from a template that has already
+    /// been loaded, as with [`LabBook::create`]
+    pub fn create_from(&self, id: &NoteID, template: &Template, author: &str) -> Result<PathBuf> {
+        let now = Local::now();
+        let number = self
+            .ids()
+            .into_iter()
+            .filter_map(|id| self.note(id).ok()?.header.number)
+            .max()
+            .map_or(1, |number| number + 1);
+        let vars = HashMap::from([
+            ("id", id.to_string()),
+            ("date", now.format("%Y-%m-%d").to_string()),
+            ("time", now.format("%H:%M").to_string()),
+            ("author", author.to_string()),
+            ("number", number.to_string()),
+        ]);
+
+        let path = self.path(id);
+        let mut file = match OpenOptions::new().write(true).create_new(true).open(&path) {
+            Ok(file) => file,
+            Err(e) => return Err(Error::io(path, e)),
+        };
+        match file.write_all(template.fill(&vars).as_bytes()) {
+            Ok(()) => Ok(path),
+            Err(e) => Err(Error::io(path, e)),
+        }
+    }
+
+    /// IDs of all of the notes in the directory, in sorted order
+    pub fn ids(&self) -> Vec<NoteID<'static>> {
+        let mut ids: Vec<NoteID<'static>> = match read_dir(&self.dir) {
+            Ok(entries) => entries
+                .filter_map(|entry| {
+                    let path = entry.ok()?.path();
+                    let extension = path.extension()?;
+                    if (extension != "md" && extension != "ipynb") || !path.is_file() {
+                        return None;
+                    }
+                    let stem = path.file_stem()?.to_str()?.to_string();
+                    NoteID::try_from(stem).ok()
+                })
+                .collect(),
+            Err(_) => Vec::new(),
+        };
+        ids.sort_by(|a, b| a.0.cmp(&b.0));
+        // A note may have both a markdown file and a notebook
+        ids.dedup_by(|a, b| a.0 == b.0);
+        ids
+    }
+
+    /// All of the notes that can be loaded, with the time that each was last
+    /// modified, most recently modified first
+    pub fn recent(&self) -> Vec<(Note<'static>, DateTime<Local>)> {
+        self.modified()
+            .into_iter()
+            .filter_map(|(id, modified)| Some((self.note(id).ok()?, modified)))
+            .collect()
+    }
+
+    /// IDs of the notes, with the time that each file was last modified,
+    /// most recently modified first. The notes aren't loaded, so this is
+    /// cheap enough to narrow down which notes to load.
+    pub fn modified(&self) -> Vec<(NoteID<'static>, DateTime<Local>)> {
+        let mut ids: Vec<(NoteID<'static>, DateTime<Local>)> = self
+            .ids()
+            .into_iter()
+            .filter_map(|id| {
+                let modified = self.path(&id).metadata().ok()?.modified().ok()?;
+                Some((id, DateTime::from(modified)))
+            })
+            .collect();
+        ids.sort_by(|(_, a), (_, b)| b.cmp(a));
+        ids
+    }
+
+    /// IDs of up to five notes with IDs similar to `id`, most similar first:
+    /// those a few edits away, or which contain `id` or are contained in it
+    /// (if the shorter of the two is at least four characters long)
+    pub fn similar(&self, id: &str) -> Vec<NoteID<'static>> {
+        let id = id.to_lowercase();
+        let threshold = (id.chars().count() / 3).max(2);
+        let mut similar: Vec<(usize, NoteID<'static>)> = self
+            .ids()
+            .into_iter()
+            .filter_map(|other| {
+                let lower = other.0.to_lowercase();
+                let (shorter, longer) = if lower.len() < id.len() {
+                    (&lower, &id)
+                } else {
+                    (&id, &lower)
+                };
+                let distance = if shorter.chars().count() >= MIN_SUBSTRING
+                    && longer.contains(shorter.as_str())
+                {
+                    0
+                } else {
+                    edit_distance(&id, &lower)
+                };
+                (distance <= threshold).then_some((distance, other))
+            })
+            .collect();
+        similar.sort_by_key(|(distance, _)| *distance);
+        similar.into_iter().take(5).map(|(_, id)| id).collect()
+    }
+
+    /// Get the notes making up a book with the note `root` as its first
+    /// chapter. The notes listed in the `contents` of a note's metadata
+    /// (or, if there is no such list, the notes it links to) are followed
+    /// up to `depth` steps from the root, with each note included once.
+    pub fn book(&self, root: NoteID, depth: usize) -> Result<Vec<Note<'static>>> {
+        let root = self.note(root.into_owned())?;
+        let mut seen: HashSet<String> = HashSet::new();
+        seen.insert((&root.id).into());
+        let mut queue = VecDeque::from([(root, 0)]);
+        let mut notes = Vec::new();
+        while let Some((note, level)) = queue.pop_front() {
+            if level < depth {
+                let explicit = !note.header.contents.is_empty();
+                let children = if explicit {
+                    note.header
+                        .contents
+                        .iter()
+                        .map(|id| NoteID::try_from(id.clone()))
+                        .collect::<Result<Vec<_>>>()?
+                } else {
+                    note.links()
+                };
+                for id in children {
+                    if !seen.insert((&id).into()) {
+                        continue;
+                    }
+                    match self.note(id) {
+                        Ok(child) => queue.push_back((child, level + 1)),
+                        // Links to notes that don't exist are left out
+                        Err(Error::NotFound { .. }) if !explicit => {}
+                        Err(e) => return Err(e),
+                    }
+                }
+            }
+            notes.push(note);
+        }
+        Ok(notes)
+    }
 }
 
 // In submodule to avoid weird compiler stack overflow
 mod theme {
     use maud::{html, Markup, Render};
 
+    #[derive(Clone)]
     pub struct Theme {
         light: bool,
     }
@@ -67,6 +454,11 @@ mod theme {
         pub fn new(light: bool) -> Theme {
             Theme { light }
         }
+
+        /// Whether this is the light theme
+        pub fn is_light(&self) -> bool {
+            self.light
+        }
     }
 
     impl Render for &Theme {
@@ -84,7 +476,48 @@ mod theme {
 
 pub use theme::Theme;
 
-pub struct NoteMetadata;
+/// Metadata for a note, given as yaml front matter at the start of the file
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct NoteMetadata {
+    /// Title of the note
+    pub title: Option<String>,
+
+    /// IDs of the notes that follow this one when it is compiled as a book
+    pub contents: Vec<String>,
+
+    /// Date the note was written
+    pub date: Option<String>,
+
+    /// Author of the note
+    pub author: Option<String>,
+
+    /// Sequential number of the experiment recorded in the note
+    pub number: Option<u64>,
+
+    /// Tags the note is filed under
+    pub tags: Vec<String>,
+}
+
+impl NoteMetadata {
+    /// Parse the front matter (if any) at the start of a markdown file,
+    /// delimited by `---` and either `---` or `...` lines
+    fn parse(markdown: &str) -> std::result::Result<NoteMetadata, serde_yaml::Error> {
+        let mut lines = markdown.lines();
+        if lines.next().map(str::trim_end) != Some("---") {
+            return Ok(NoteMetadata::default());
+        }
+        let yaml: Vec<&str> = lines
+            .take_while(|line| !matches!(line.trim_end(), "---" | "..."))
+            .collect();
+        let yaml = yaml.join("\n");
+        if yaml.trim().is_empty() {
+            Ok(NoteMetadata::default())
+        } else {
+            serde_yaml::from_str(&yaml)
+        }
+    }
+}
 
 /// A lab note, consisting of a header containing metadata and
 /// a body contianing a markdown string. Can be rendered as html.
@@ -92,42 +525,282 @@ pub struct Note<'a> {
     pub id: NoteID<'a>,
     pub header: NoteMetadata,
     pub body: Markdown<String>,
+
+    /// Directory that the notes this note transcludes, and the data it
+    /// includes, are read from: the directory holding the note, or the
+    /// root of the notebook for journal entries. Notes that weren't loaded
+    /// from a file read them relative to the current directory.
+    pub dir: Option<PathBuf>,
+}
+
+/// Serializes the note's ID, metadata and markdown source, along with the
+/// headings of its outline and the IDs of the notes it links to
+impl Serialize for Note<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        let mut note = serializer.serialize_struct("Note", 4)?;
+        note.serialize_field("id", &self.id)?;
+        note.serialize_field("metadata", &self.header)?;
+        note.serialize_field("markdown", &self.body.0)?;
+        note.serialize_field("links", &self.links())?;
+        note.end()
+    }
 }
 
 impl Note<'_> {
     /// Load note from a file. Should contain yaml-encoded metadata
-    /// followed by markdown body.
+    /// followed by markdown body, or be a Jupyter notebook (with the
+    /// extension `.ipynb`), whose cells and saved outputs become the body.
     pub fn load(id: NoteID, path: PathBuf) -> Result<Note> {
-        let body = Markdown(read_to_string(path).or(Err(Error::NotFound))?);
+        let source = match read_to_string(&path) {
+            Ok(source) => source,
+            Err(e) => return Err(Error::io(path, e)),
+        };
+        let source = if path.extension().is_some_and(|ext| ext == "ipynb") {
+            match notebook::to_markdown(&source) {
+                Ok(markdown) => markdown,
+                Err(source) => return Err(Error::InvalidNotebook { path, source }),
+            }
+        } else {
+            source
+        };
+        let dir = path.parent().map(Path::to_path_buf);
         Ok(Note {
-            id,
-            header: NoteMetadata,
-            body,
+            dir,
+            ..Note::parse(id, source)
         })
     }
 
-    /// Render the note to html
+    /// Load a note as with [`Note::load`], but fail with
+    /// [`Error::InvalidMetadata`] if its front matter can't be parsed,
+    /// rather than ignoring it
+    pub fn load_strict(id: NoteID, path: PathBuf) -> Result<Note> {
+        let note = Note::load(id, path.clone())?;
+        match note.check_metadata() {
+            Err(Error::InvalidMetadata { source, .. }) => Err(Error::InvalidMetadata {
+                path: Some(path),
+                source,
+            }),
+            _ => Ok(note),
+        }
+    }
+
+    /// Parse note from a string containing yaml-encoded metadata
+    /// followed by markdown body. Front matter that can't be parsed is
+    /// ignored, and reported with the note's diagnostics.
+    pub fn parse(id: NoteID, source: String) -> Note {
+        let header = NoteMetadata::parse(&source).unwrap_or_default();
+        Note {
+            id,
+            header,
+            body: Markdown(source),
+            dir: None,
+        }
+    }
+
+    /// Check that the note's front matter parses, which [`Note::parse`]
+    /// doesn't require
+    pub fn check_metadata(&self) -> Result<()> {
+        NoteMetadata::parse(&self.body.0)
+            .map(|_| ())
+            .map_err(|source| Error::InvalidMetadata { path: None, source })
+    }
+
+    /// Problem with the note's front matter, if it couldn't be parsed
+    fn metadata_diagnostic(&self) -> Option<Diagnostic> {
+        let error = NoteMetadata::parse(&self.body.0).err()?;
+        Some(Diagnostic::front_matter(&self.body.0, &error))
+    }
+
+    /// Add a warning about the note's front matter, if it couldn't be
+    /// parsed, to those from rendering it
+    fn with_metadata_warning(
+        &self,
+        (output, mut warnings): (String, Vec<String>),
+    ) -> (String, Vec<String>) {
+        if let Some(diagnostic) = self.metadata_diagnostic() {
+            let warning = format!("line {}: {}", diagnostic.line, diagnostic.message);
+            warnings.insert(0, warning);
+        }
+        (output, warnings)
+    }
+
+    /// Title of the note: the title given in its metadata, or else a level 1
+    /// heading at its start, or failing that its ID
+    pub fn title(&self) -> String {
+        self.header
+            .title
+            .clone()
+            .or_else(|| self.body.title())
+            .unwrap_or_else(|| self.id.to_string())
+    }
+
+    /// IDs of the other notes that this note links to, in order of first
+    /// appearance
+    pub fn links(&self) -> Vec<NoteID<'static>> {
+        self.body
+            .note_links()
+            .into_iter()
+            .filter_map(|id| NoteID::try_from(id).ok())
+            .collect()
+    }
+
+    /// Render the note to html, with a banner listing any problems found
+    /// while rendering
     pub fn render_html(&self, theme: &Theme) -> Markup {
+        self.render_html_with(theme, &HtmlOptions::default())
+    }
+
+    /// Render the note to html with the given options
+    pub fn render_html_with(&self, theme: &Theme, opts: &HtmlOptions) -> Markup {
+        self.render_page(theme, opts, None)
+    }
+
+    /// Render the body of the note to html with the given options, without
+    /// the rest of the page, along with any problems found while rendering.
+    /// Code is coloured to match the theme. Data and transcluded notes are
+    /// read from the note's directory unless `opts` gives another.
+    pub fn render_body_html(&self, theme: &Theme, opts: &HtmlOptions) -> (Markup, Vec<Diagnostic>) {
+        let (body, mut diagnostics) = self.body.render_html_with_theme(
+            theme,
+            &HtmlOptions {
+                data_dir: opts.data_dir.clone().or_else(|| self.dir.clone()),
+                note_id: opts.note_id.clone().or_else(|| Some(self.id.to_string())),
+                ..opts.clone()
+            },
+        );
+        diagnostics.splice(0..0, self.metadata_diagnostic());
+        (body, diagnostics)
+    }
+
+    /// Render the note to an html page, with an optional navigation bar
+    /// above the note
+    fn render_page(&self, theme: &Theme, opts: &HtmlOptions, nav: Option<Markup>) -> Markup {
+        let (body, diagnostics) = self.render_body_html(theme, opts);
         html! {
             (DOCTYPE)
             head {
                 (theme)
-                link rel="stylesheet" href="https://cdn.jsdelivr.net/npm/katex@0.11.1/dist/katex.min.css" integrity="sha384-zB1R0rpPzHqg7Kpt0Aljp8JPLqbXI3bhnPWROx27a9N0Ll6ZP/+DiW/UqRcLbRjq" crossorigin="anonymous";
+                @if opts.math.needs_stylesheet() {
+                    link rel="stylesheet" href="https://cdn.jsdelivr.net/npm/katex@0.11.1/dist/katex.min.css" integrity="sha384-zB1R0rpPzHqg7Kpt0Aljp8JPLqbXI3bhnPWROx27a9N0Ll6ZP/+DiW/UqRcLbRjq" crossorigin="anonymous";
+                }
+                style {
+                    ".math-error { color: #dc322f; }"
+                    ".permalink { opacity: 0; text-decoration: none; }"
+                    ":is(h1, h2, h3, h4, h5, h6):hover .permalink { opacity: 0.5; }"
+                    "aside.toc { float: right; position: sticky; top: 1em; max-width: 16em; margin: 0 0 1em 1em; font-size: 0.9em; }"
+                }
             }
             body {
-                (self.body)
+                @if !diagnostics.is_empty() {
+                    details class="diagnostics" style="border: 1px solid #b58900; padding: 0.5em 1em;" {
+                        summary style="color: #b58900;" {
+                            "Warning: " (diagnostics.len())
+                            @if diagnostics.len() == 1 { " problem" } @else { " problems" }
+                            " rendering this note"
+                        }
+                        ul {
+                            @for diagnostic in &diagnostics {
+                                li {
+                                    "Line " (diagnostic.line) ", column " (diagnostic.column) ": "
+                                    code { (diagnostic.source) }
+                                    br;
+                                    (diagnostic.message)
+                                }
+                            }
+                        }
+                    }
+                }
+                @if let Some(nav) = nav {
+                    nav { (nav) }
+                }
+                (body)
             }
         }
     }
 
+    /// Problems found while rendering the note to html with the given
+    /// options, such as formulae that could not be rendered. This renders
+    /// the whole note, so use [`Note::render_body_html`] instead when the
+    /// html is needed too.
+    pub fn diagnostics(&self, opts: &HtmlOptions) -> Vec<Diagnostic> {
+        // The theme only changes the colours of highlighted code
+        self.render_body_html(&Theme::new(false), opts).1
+    }
+
     /// Render the note to latex
     pub fn render_tex(&self) -> String {
-        self.body.render_tex()
+        self.render_tex_with(&TexOptions::default()).0
+    }
+
+    /// Render the note to latex with the given options, along with
+    /// warnings about any content that could not be translated
+    pub fn render_tex_with(&self, opts: &TexOptions) -> (String, Vec<String>) {
+        self.with_metadata_warning(self.body.render_tex_with(&TexOptions {
+            data_dir: opts.data_dir.clone().or_else(|| self.dir.clone()),
+            note_id: opts.note_id.clone().or_else(|| Some(self.id.to_string())),
+            ..opts.clone()
+        }))
     }
+
+    /// Render the note to typst
+    pub fn render_typst(&self) -> String {
+        self.render_typst_with(&TypstOptions::default()).0
+    }
+
+    /// Render the note to typst with the given options, along with
+    /// warnings about any content that could not be translated
+    pub fn render_typst_with(&self, opts: &TypstOptions) -> (String, Vec<String>) {
+        self.with_metadata_warning(self.body.render_typst_with(&TypstOptions {
+            data_dir: opts.data_dir.clone().or_else(|| self.dir.clone()),
+            note_id: opts.note_id.clone().or_else(|| Some(self.id.to_string())),
+            ..opts.clone()
+        }))
+    }
+
+    /// Render several notes as a single latex document, with each note as a
+    /// chapter (or a section, if `chapters` is false)
+    pub fn render_tex_book(
+        notes: &[Note],
+        opts: &TexOptions,
+        chapters: bool,
+    ) -> (String, Vec<String>) {
+        let book: Vec<TexChapter<String>> = notes
+            .iter()
+            .map(|note| TexChapter {
+                id: (&note.id).into(),
+                title: note.header.title.as_deref(),
+                body: &note.body,
+            })
+            .collect();
+        // The notes of a book are all in the same directory
+        let opts = TexOptions {
+            data_dir: (opts.data_dir.clone())
+                .or_else(|| notes.first().and_then(|note| note.dir.clone())),
+            ..opts.clone()
+        };
+        let (latex, mut warnings) = markdown::render_tex_book(&book, &opts, chapters);
+        for note in notes {
+            if let Some(diagnostic) = note.metadata_diagnostic() {
+                warnings.push(format!(
+                    "{}, line {}: {}",
+                    note.id, diagnostic.line, diagnostic.message
+                ));
+            }
+        }
+        (latex, warnings)
+    }
+}
+
+/// Shortest ID that makes a note similar to any ID containing it, or to any
+/// ID it contains, when suggesting notes
+const MIN_SUBSTRING: usize = 4;
+
+/// Format a time in RFC 3339 format, to the second, as in feeds and the API
+fn timestamp(time: &DateTime<Local>) -> String {
+    time.to_rfc3339_opts(SecondsFormat::Secs, true)
 }
 
-const ALLOWED_CHARS: &'static [u8] =
-    b"abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ0123456789-_";
+const ALLOWED_CHARS: &[u8] = b"abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ0123456789-_";
 const MAX_SIZE: usize = 128;
 
 /// Identifier for a apecific note. A given identifier corresponds
@@ -139,7 +812,7 @@ impl<'a> TryFrom<&'a str> for NoteID<'a> {
     type Error = Error;
 
     fn try_from(string: &'a str) -> Result<Self> {
-        if string.len() > MAX_SIZE || string.len() < 1 {
+        if string.len() > MAX_SIZE || string.is_empty() {
             Err(Error::InvalidLength)
         } else if !string.bytes().all(|c| ALLOWED_CHARS.contains(&c)) {
             Err(Error::InvalidCharacter)
@@ -153,7 +826,7 @@ impl TryFrom<String> for NoteID<'static> {
     type Error = Error;
 
     fn try_from(string: String) -> Result<Self> {
-        if string.len() > MAX_SIZE || string.len() < 1 {
+        if string.len() > MAX_SIZE || string.is_empty() {
             Err(Error::InvalidLength)
         } else if !string.bytes().all(|c| ALLOWED_CHARS.contains(&c)) {
             Err(Error::InvalidCharacter)
@@ -163,33 +836,174 @@ impl TryFrom<String> for NoteID<'static> {
     }
 }
 
+impl NoteID<'_> {
+    /// Convert into an ID that owns its string
+    pub fn into_owned(self) -> NoteID<'static> {
+        NoteID(Cow::Owned(self.0.into_owned()))
+    }
+}
+
+impl NoteID<'static> {
+    /// A Zettelkasten-style ID made from the current local time,
+    /// e.g. `20240131154502`
+    pub fn timestamp() -> NoteID<'static> {
+        NoteID(Cow::Owned(Local::now().format("%Y%m%d%H%M%S").to_string()))
+    }
+}
+
 impl Display for NoteID<'_> {
     fn fmt(&self, f: &mut Formatter) -> fmt::Result {
         write!(f, "{}", self.0)
     }
 }
 
-impl Into<String> for &NoteID<'_> {
-    fn into(self) -> String {
-        String::from(self.0.as_ref())
+impl From<&NoteID<'_>> for String {
+    fn from(id: &NoteID<'_>) -> String {
+        String::from(id.0.as_ref())
     }
 }
 
-impl<'a> Into<&'a str> for &'a NoteID<'a> {
-    fn into(self) -> &'a str {
-        &self.0
+impl Serialize for NoteID<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.0)
+    }
+}
+
+impl<'a> From<&'a NoteID<'a>> for &'a str {
+    fn from(id: &'a NoteID<'a>) -> &'a str {
+        &id.0
     }
 }
 
 /// Returns an instance of `NoteID` if the path segment is a valid ID.
-/// Otherwise returns the invalid ID as the `Err` value.
+/// Otherwise returns the reason that the ID is invalid as the `Err` value.
 impl<'a> FromParam<'a> for NoteID<'a> {
-    type Error = &'a str;
+    type Error = Error;
 
-    fn from_param(param: &'a str) -> std::result::Result<NoteID<'a>, Self::Error> {
-        match NoteID::try_from(param as &str) {
-            Ok(id) => Ok(id),
-            Err(_) => Err(param),
+    fn from_param(param: &'a str) -> Result<NoteID<'a>> {
+        NoteID::try_from(param)
+    }
+}
+
+/// Levenshtein distance between two strings
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = diagonal + usize::from(ca != *cb);
+            diagonal = row[j + 1];
+            row[j + 1] = substitution.min(row[j] + 1).min(row[j + 1] + 1);
         }
     }
+    row[b.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_dir::TestDir;
+
+    fn note(source: &str) -> Note<'static> {
+        Note::parse(NoteID::try_from("test").unwrap(), source.to_string())
+    }
+
+    #[test]
+    fn parses_front_matter() {
+        let note = note("---\ntitle: Run 3\nnumber: 3\ntags: [optics, laser]\n---\n# Body\n");
+        assert_eq!(note.header.title.as_deref(), Some("Run 3"));
+        assert_eq!(note.header.number, Some(3));
+        assert_eq!(note.header.tags, ["optics", "laser"]);
+        assert!(note.diagnostics(&HtmlOptions::default()).is_empty());
+    }
+
+    #[test]
+    fn ignores_invalid_front_matter() {
+        for source in [
+            "---\ntitle: Run 3\ntags: foo\n---\nBody\n",
+            "---\ntitle: Run 3\nnumber: abc\n---\nBody\n",
+            "---\ntitle: [unclosed\n---\nBody\n",
+        ] {
+            let note = note(source);
+            assert!(note.header.title.is_none());
+            assert!(note.body.0.ends_with("Body\n"));
+
+            let diagnostics = note.diagnostics(&HtmlOptions::default());
+            assert_eq!(diagnostics.len(), 1, "{}", source);
+            assert!(diagnostics[0]
+                .message
+                .starts_with("invalid front matter, which is ignored"));
+            assert!(diagnostics[0].line > 1);
+
+            let (latex, warnings) = note.render_tex_with(&TexOptions::default());
+            assert!(latex.contains("Body"));
+            assert_eq!(warnings.len(), 1);
+
+            // Unless the front matter is checked
+            assert!(matches!(
+                note.check_metadata(),
+                Err(Error::InvalidMetadata { path: None, .. })
+            ));
+        }
+        assert!(note("---\ntitle: Run 3\n---\n").check_metadata().is_ok());
+    }
+
+    #[test]
+    fn points_at_the_invalid_field() {
+        let diagnostics =
+            note("---\ntitle: Run 3\nnumber: abc\n---\n").diagnostics(&HtmlOptions::default());
+        assert_eq!(diagnostics[0].line, 3);
+        assert_eq!(diagnostics[0].source, "abc");
+        assert!(!diagnostics[0].message.contains("at line"));
+    }
+
+    #[test]
+    fn colours_code_to_match_the_theme() {
+        let note = note("```rust\nfn main() {}\n```\n");
+        let light = note.render_html(&Theme::new(true)).into_string();
+        let dark = note.render_html(&Theme::new(false)).into_string();
+        assert!(light.contains("solarized-light") && light.contains("#fdf6e3"));
+        assert!(dark.contains("solarized-dark") && dark.contains("#002b36"));
+    }
+
+    #[test]
+    fn keeps_paths_out_of_error_messages() {
+        let path = PathBuf::from("/srv/notes/run-3.md");
+        let source = || io::Error::from(io::ErrorKind::InvalidData);
+        let errors = [
+            Error::io(path.clone(), io::Error::from(io::ErrorKind::NotFound)),
+            Error::io(path.clone(), source()),
+            Error::InvalidNotebook {
+                path: path.clone(),
+                source: serde_json::from_str::<()>("").unwrap_err(),
+            },
+        ];
+        for error in errors {
+            let message = error.message();
+            assert!(message.contains("\"run-3\""), "{}", message);
+            assert!(!message.contains("/srv"), "{}", message);
+        }
+    }
+
+    #[test]
+    fn suggests_similar_ids() {
+        let dir = TestDir::new(&[
+            ("protocol.md", ""),
+            ("experiment-3.md", ""),
+            ("ab.md", ""),
+            ("analysis.md", ""),
+        ]);
+        let book = LabBook::new(dir.path().to_path_buf());
+        let similar = |id| -> Vec<String> { book.similar(id).iter().map(String::from).collect() };
+
+        assert_eq!(similar("Protocl"), ["protocol"]);
+        assert_eq!(similar("experiment"), ["experiment-3"]);
+        assert_eq!(similar("experiment-3-repeat"), ["experiment-3"]);
+        // Short IDs aren't close to every ID that happens to contain them
+        assert!(!similar("ex").contains(&"experiment-3".to_string()));
+        assert!(!similar("analysis-ab").contains(&"ab".to_string()));
+        assert_eq!(similar("ac"), ["ab"]);
+    }
 }
@@ -1,179 +1,1034 @@
 use maud::{Markup, PreEscaped, Render};
 use pulldown_cmark::{
-    html, Alignment, CodeBlockKind, CowStr, Event, LinkType, Options, Parser, Tag, TagEnd,
+    html, Alignment, BrokenLink, BrokenLinkCallback, CodeBlockKind, CowStr, Event, HeadingLevel,
+    LinkType, Options, Parser, Tag, TagEnd,
 };
 use pulldown_cmark_escape::{escape_href, escape_html};
 use regex::Regex;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::LazyLock;
+
+use crate::Theme;
+
+mod cache;
+mod data;
+mod graphviz;
+mod highlight;
+mod plot;
+mod toc;
+mod transclude;
+mod typst;
+
+pub(crate) use graphviz::strip_prolog;
+use highlight::{CodeInfo, HighlightMiddleware};
+pub(crate) use toc::TocEntry;
+
+pub use typst::TypstOptions;
 
 /// Renders a block of Markdown using `pulldown-cmark`.
 pub struct Markdown<T: AsRef<str>>(pub T);
 
+/// Options controlling the html output of `Markdown::render_html_with`.
+#[derive(Clone, Debug, Default)]
+pub struct HtmlOptions {
+    /// How KaTeX outputs math
+    pub math: MathOutput,
+
+    /// Show a table of contents in a sidebar, unless the note places one
+    /// with a `[TOC]` marker
+    pub toc: bool,
+
+    /// Directory that notes transcluded into the note, and CSV data it
+    /// includes, are read from. Without it, they are read relative to the
+    /// current directory.
+    pub data_dir: Option<PathBuf>,
+
+    /// ID of the note being rendered, so that a note which transcludes
+    /// itself is caught before any of it is inlined
+    pub note_id: Option<String>,
+}
+
+/// Output format for math rendered by KaTeX.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum MathOutput {
+    /// HTML for display, with MathML for accessibility. Needs the KaTeX
+    /// stylesheet and fonts.
+    #[default]
+    HtmlAndMathml,
+
+    /// HTML only. Needs the KaTeX stylesheet and fonts.
+    Html,
+
+    /// MathML only, which displays without any stylesheet.
+    Mathml,
+}
+
+impl MathOutput {
+    /// Whether the output needs the KaTeX stylesheet to display properly
+    pub fn needs_stylesheet(&self) -> bool {
+        *self != MathOutput::Mathml
+    }
+}
+
+impl FromStr for MathOutput {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "htmlandmathml" | "html-and-mathml" => Ok(MathOutput::HtmlAndMathml),
+            "html" => Ok(MathOutput::Html),
+            "mathml" => Ok(MathOutput::Mathml),
+            _ => Err(format!(
+                "unknown math output \"{}\" (expected htmlAndMathml, html, or mathml)",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for MathOutput {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            MathOutput::HtmlAndMathml => "htmlAndMathml",
+            MathOutput::Html => "html",
+            MathOutput::Mathml => "mathml",
+        })
+    }
+}
+
+/// Options controlling the latex output of `Markdown::render_tex_with`.
+#[derive(Clone, Debug, Default)]
+pub struct TexOptions {
+    /// Use `booktabs` rules instead of `\hline` in tables
+    pub booktabs: bool,
+
+    /// Typeset tables as a `longtable`, which may break across pages
+    pub longtable: bool,
+
+    /// Cite arXiv and DOI references, and list them in a bibliography at
+    /// the end of the document
+    pub bibliography: bool,
+
+    /// Write only the body of the document, without the preamble or
+    /// `\begin{document}`, so that it can be `\input` into another document
+    pub fragment: bool,
+
+    /// Write a table of contents at the start of the document. One can
+    /// also be placed with a `[TOC]` marker.
+    pub toc: bool,
+
+    /// Directory to write Graphviz diagrams (rendered as pdf) and plots
+    /// (drawn with pgfplots) to. The latex includes them by this path, so it
+    /// should be absolute or relative to where the latex is compiled.
+    /// Without it, diagrams and plots are written as code.
+    pub diagram_dir: Option<PathBuf>,
+
+    /// Directory that the latex includes diagrams and plots from, in place
+    /// of `diagram_dir`, for when they will be moved before it is compiled
+    pub diagram_path: Option<PathBuf>,
+
+    /// Directory that notes transcluded into the note, and CSV data it
+    /// includes, are read from. Without it, they are read relative to the
+    /// current directory.
+    pub data_dir: Option<PathBuf>,
+
+    /// ID of the note being rendered, so that a note which transcludes
+    /// itself is caught before any of it is inlined
+    pub note_id: Option<String>,
+}
+
+/// A note to be written as one chapter of a latex book.
+pub struct TexChapter<'a, T: AsRef<str>> {
+    /// ID of the note, used to label the chapter
+    pub id: &'a str,
+
+    /// Title of the chapter. If not given, a level 1 heading at the start
+    /// of the note is used, or failing that the ID.
+    pub title: Option<&'a str>,
+
+    pub body: &'a Markdown<T>,
+}
+
+impl<T: AsRef<str>> Markdown<T> {
+    /// Parse the markdown, resolving arXiv and DOI references.
+    fn parser(&self) -> Parser<'_, References> {
+        Parser::new_with_broken_link_callback(self.0.as_ref(), Options::all(), Some(References))
+    }
+
+    /// Parse the markdown along with the byte range of each event, with
+    /// the notes it transcludes and the CSV data it includes (both read
+    /// from `data_dir`) expanded. `id` is the ID of this note, if it has
+    /// one, which can't be transcluded into itself.
+    fn events(
+        &self,
+        data_dir: Option<&Path>,
+        id: Option<&str>,
+        diagnostics: &mut Vec<Diagnostic>,
+    ) -> Vec<(Event<'_>, Range<usize>)> {
+        let dir = data_dir.unwrap_or(Path::new(""));
+        let events = self.parser().into_offset_iter().collect();
+        let events = transclude::transclude(events, self.0.as_ref(), dir, id, diagnostics);
+        data::include_data(events, self.0.as_ref(), dir, diagnostics)
+    }
+
+    /// Parse the markdown for latex or typst, with transcluded notes and
+    /// CSV data expanded. Anything that can't be included becomes a warning.
+    fn events_with_warnings(
+        &self,
+        data_dir: Option<&Path>,
+        id: Option<&str>,
+    ) -> (Vec<Event<'_>>, Vec<String>) {
+        let mut diagnostics = Vec::new();
+        let events = self.events(data_dir, id, &mut diagnostics);
+        let warnings = diagnostics
+            .into_iter()
+            .map(|diagnostic| format!("line {}: {}", diagnostic.line, diagnostic.message))
+            .collect();
+        (events.into_iter().map(|(e, _)| e).collect(), warnings)
+    }
+}
+
 impl<T: AsRef<str>> Render for Markdown<T> {
     fn render(&self) -> Markup {
-        // Compile regular expressions to match arXiv and DOI references
-        let new_arxiv = Regex::new(r"^ar[xX]iv:([0-9]{4}[.][0-9]{4,}(v[0-9]+)?)$").unwrap();
-        let old_arxiv = Regex::new(r"^(ar[xX]iv:)?([a-zA-Z.-]+/[0-9]{7}(v[0-9]+)?)$").unwrap();
-        let doi = Regex::new(r"^(doi:)?(10[.][0-9.]+/[0-9a-zA-Z()._-]+)$").unwrap();
-
-        let mut reference_callback = |link: pulldown_cmark::BrokenLink| {
-            let reference = link.reference;
-            if let Some(c) = new_arxiv.captures(&reference) {
-                Some((
-                    format!("https://arxiv.org/abs/{}", c.get(1).unwrap().as_str()),
-                    format!("arXiv:{}", c.get(1).unwrap().as_str()),
-                ))
-            } else if let Some(c) = old_arxiv.captures(&reference) {
-                Some((
-                    format!("https://arxiv.org/abs/{}", c.get(2).unwrap().as_str()),
-                    format!("{}", c.get(2).unwrap().as_str()),
-                ))
-            } else if let Some(c) = doi.captures(&reference) {
-                Some((
-                    format!("https://dx.doi.org/{}", c.get(2).unwrap().as_str()),
-                    format!("doi:{}", c.get(2).unwrap().as_str()),
-                ))
-            } else {
-                None
-            }
-            .map(|(url, label)| {
-                (url.into(), label.into()) as (pulldown_cmark::CowStr, pulldown_cmark::CowStr)
-            })
+        self.render_html().0
+    }
+}
+
+/// A problem found while rendering markdown, such as a formula that
+/// KaTeX could not render.
+#[derive(Clone, Debug)]
+pub struct Diagnostic {
+    /// Byte range of the problem in the markdown source
+    pub range: Range<usize>,
+
+    /// Line of the start of the problem, counting from 1
+    pub line: usize,
+
+    /// Column (in characters) of the start of the problem, counting from 1
+    pub column: usize,
+
+    /// Text that could not be rendered
+    pub source: String,
+
+    /// Description of the problem
+    pub message: String,
+}
+
+impl Diagnostic {
+    fn new(markdown: &str, range: Range<usize>, source: &str, message: String) -> Diagnostic {
+        let before = &markdown[..range.start];
+        let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+        Diagnostic {
+            line: before.matches('\n').count() + 1,
+            column: before[line_start..].chars().count() + 1,
+            range,
+            source: source.to_string(),
+            message,
+        }
+    }
+}
+
+impl Diagnostic {
+    /// A problem with the yaml front matter at the start of the markdown,
+    /// which begins on its second line.
+    pub(crate) fn front_matter(markdown: &str, error: &serde_yaml::Error) -> Diagnostic {
+        let yaml_start = markdown.find('\n').map_or(markdown.len(), |i| i + 1);
+        let mut start =
+            (yaml_start + error.location().map_or(0, |l| l.index())).min(markdown.len());
+        while !markdown.is_char_boundary(start) {
+            start -= 1;
+        }
+        let end = markdown[start..]
+            .find('\n')
+            .map_or(markdown.len(), |i| start + i);
+        // The location is given by the diagnostic, counting from the start
+        // of the file rather than of the yaml
+        let message = error.to_string();
+        let message = match message.rfind(" at line ") {
+            Some(i) => &message[..i],
+            None => &message,
         };
+        Diagnostic::new(
+            markdown,
+            start..end,
+            &markdown[start..end],
+            format!("invalid front matter, which is ignored: {}", message),
+        )
+    }
+}
+
+impl<T: AsRef<str>> Markdown<T> {
+    /// Render to html, along with any problems found while rendering.
+    pub fn render_html(&self) -> (Markup, Vec<Diagnostic>) {
+        self.render_html_with(&HtmlOptions::default())
+    }
 
-        // Generate raw HTML
-        let parser = Parser::new_with_broken_link_callback(
-            self.0.as_ref(),
-            Options::all(),
-            Some(&mut reference_callback),
+    /// Render to html with the given options, along with any problems
+    /// found while rendering. Code is coloured for the dark theme.
+    pub fn render_html_with(&self, opts: &HtmlOptions) -> (Markup, Vec<Diagnostic>) {
+        self.render_html_with_theme(&Theme::new(false), opts)
+    }
+
+    /// Render to html with the given options, colouring code to match the
+    /// theme, along with any problems found while rendering.
+    pub fn render_html_with_theme(
+        &self,
+        theme: &Theme,
+        opts: &HtmlOptions,
+    ) -> (Markup, Vec<Diagnostic>) {
+        let mut diagnostics = Vec::new();
+        let mut events = self.events(
+            opts.data_dir.as_deref(),
+            opts.note_id.as_deref(),
+            &mut diagnostics,
+        );
+        let headings = toc::heading_ids(&mut events, |(e, _)| e, |(e, _)| e);
+        let (events, marker) = toc::link_headings(events, &headings);
+        let markdown = self.0.as_ref();
+        let events = render_blocks(
+            events,
+            markdown,
+            &mut diagnostics,
+            graphviz::is_diagram,
+            graphviz::render_svg,
+        );
+        let data_dir = opts.data_dir.as_deref().unwrap_or(Path::new(""));
+        let events = render_blocks(
+            events,
+            markdown,
+            &mut diagnostics,
+            plot::is_plot,
+            |source| plot::render_svg(source, data_dir),
         );
+        let (events, captions) = TableCaption::extract(events, |(e, _)| e);
 
-        let mut katex = KatexMiddleware::new();
-        let parser = parser.filter_map(move |e| katex.map(e));
+        let mut katex = KatexMiddleware::new(self.0.as_ref(), opts.math);
+        let parser = CaptionMiddleware::new(
+            HighlightMiddleware::new(
+                events.into_iter().map(|(e, range)| katex.map(e, range)),
+                theme.is_light(),
+            ),
+            captions,
+        );
 
+        // Generate raw HTML, after the table of contents if it goes in a
+        // sidebar
         let mut unsafe_html = String::new();
+        if opts.toc && !marker && !headings.is_empty() {
+            unsafe_html.push_str("<aside class=\"toc\">");
+            unsafe_html.push_str(&toc::toc_html(&headings));
+            unsafe_html.push_str("</aside>\n");
+        }
         html::push_html(&mut unsafe_html, parser);
 
         // Sanitize it with ammonia
         //let safe_html = ammonia::clean(&unsafe_html);
-        PreEscaped(unsafe_html)
+        diagnostics.extend(katex.diagnostics);
+        diagnostics.sort_by_key(|diagnostic| diagnostic.range.start);
+        (PreEscaped(unsafe_html), diagnostics)
+    }
+
+    /// Problems found while rendering to html.
+    pub fn diagnostics(&self) -> Vec<Diagnostic> {
+        self.render_html().1
     }
 }
 
-struct KatexMiddleware;
+/// Broken link callback that turns arXiv and DOI references into links.
+struct References;
 
-impl KatexMiddleware {
-    fn new() -> KatexMiddleware {
-        KatexMiddleware
+impl<'input> BrokenLinkCallback<'input> for References {
+    fn handle_broken_link(
+        &mut self,
+        link: BrokenLink<'input>,
+    ) -> Option<(CowStr<'input>, CowStr<'input>)> {
+        static NEW_ARXIV: LazyLock<Regex> =
+            LazyLock::new(|| Regex::new(r"^ar[xX]iv:([0-9]{4}[.][0-9]{4,}(v[0-9]+)?)$").unwrap());
+        static OLD_ARXIV: LazyLock<Regex> = LazyLock::new(|| {
+            Regex::new(r"^(ar[xX]iv:)?([a-zA-Z.-]+/[0-9]{7}(v[0-9]+)?)$").unwrap()
+        });
+        static DOI: LazyLock<Regex> =
+            LazyLock::new(|| Regex::new(r"^(doi:)?(10[.][0-9.]+/[0-9a-zA-Z()._-]+)$").unwrap());
+        let reference = link.reference;
+        if let Some(c) = NEW_ARXIV.captures(&reference) {
+            Some((
+                format!("https://arxiv.org/abs/{}", c.get(1).unwrap().as_str()),
+                format!("arXiv:{}", c.get(1).unwrap().as_str()),
+            ))
+        } else if let Some(c) = OLD_ARXIV.captures(&reference) {
+            Some((
+                format!("https://arxiv.org/abs/{}", c.get(2).unwrap().as_str()),
+                c.get(2).unwrap().as_str().to_string(),
+            ))
+        } else {
+            DOI.captures(&reference).map(|c| {
+                (
+                    format!("https://dx.doi.org/{}", c.get(2).unwrap().as_str()),
+                    format!("doi:{}", c.get(2).unwrap().as_str()),
+                )
+            })
+        }
+        .map(|(url, label)| (url.into(), label.into()))
     }
+}
 
-    fn map<'a>(&'_ mut self, event: Event<'a>) -> Option<Event<'a>> {
-        match event {
-            Event::DisplayMath(text) => {
-                let opts = katex::Opts::builder().display_mode(true).build().unwrap();
-                Some(Event::Html(CowStr::from(
-                    katex::render_with_opts(text.as_ref(), opts).unwrap_or_else(|e| match e {
-                        katex::Error::JsExecError(s) => {
-                            format!("<div class=\"todo\">{}</div>", s)
+/// Caption attached to a table by an adjacent `Table: caption {#tbl:id}` paragraph.
+struct TableCaption {
+    text: String,
+    label: Option<String>,
+}
+
+impl TableCaption {
+    /// Parse the text of a paragraph as a table caption.
+    fn parse(text: &str) -> Option<TableCaption> {
+        static LABEL: LazyLock<Regex> =
+            LazyLock::new(|| Regex::new(r"\s*\{#([^\s{}]+)\}\s*$").unwrap());
+        let text = text.trim().strip_prefix("Table:")?.trim();
+        match LABEL.captures(text) {
+            Some(c) => Some(TableCaption {
+                text: text[..c.get(0).unwrap().start()].to_string(),
+                label: Some(c.get(1).unwrap().as_str().to_string()),
+            }),
+            None => Some(TableCaption {
+                text: text.to_string(),
+                label: None,
+            }),
+        }
+    }
+
+    /// Removes caption paragraphs that directly precede or follow a table
+    /// from a stream of items containing events. Returns the remaining
+    /// items, along with the caption (if any) of each table in order of
+    /// appearance.
+    fn extract<'a, T: Clone>(
+        items: Vec<T>,
+        event: fn(&T) -> &Event<'a>,
+    ) -> (Vec<T>, Vec<Option<TableCaption>>) {
+        // Find the caption of the paragraph starting at item `i`, and the
+        // index of the item following the paragraph
+        let caption_at = |i: usize| -> Option<(TableCaption, usize)> {
+            if !matches!(items.get(i).map(event), Some(Event::Start(Tag::Paragraph))) {
+                return None;
+            }
+            let mut text = String::new();
+            for (j, item) in items.iter().enumerate().skip(i + 1) {
+                match event(item) {
+                    Event::Text(t) | Event::Code(t) => text.push_str(t),
+                    Event::InlineMath(t) => {
+                        text.push('$');
+                        text.push_str(t);
+                        text.push('$');
+                    }
+                    Event::SoftBreak => text.push(' '),
+                    Event::End(TagEnd::Paragraph) => {
+                        return TableCaption::parse(&text).map(|c| (c, j + 1));
+                    }
+                    _ => return None,
+                }
+            }
+            None
+        };
+
+        let mut kept = Vec::with_capacity(items.len());
+        let mut captions = Vec::new();
+        let mut pending: Option<TableCaption> = None;
+        let mut i = 0;
+        while i < items.len() {
+            if let Some((caption, next)) = caption_at(i) {
+                if matches!(
+                    items.get(next).map(event),
+                    Some(Event::Start(Tag::Table(_)))
+                ) {
+                    pending = Some(caption);
+                    i = next;
+                    continue;
+                }
+            }
+            match event(&items[i]) {
+                Event::Start(Tag::Table(_)) => captions.push(pending.take()),
+                Event::End(TagEnd::Table) => {
+                    let last = captions.last_mut().unwrap();
+                    if last.is_none() {
+                        if let Some((caption, next)) = caption_at(i + 1) {
+                            *last = Some(caption);
+                            kept.push(items[i].clone());
+                            i = next;
+                            continue;
                         }
-                        _ => panic!("{}", e),
-                    }),
-                )))
-            }
-            Event::InlineMath(text) => Some(Event::Html(CowStr::from(
-                katex::render(text.as_ref()).unwrap_or_else(|e| match e {
-                    katex::Error::JsExecError(s) => {
-                        format!("<span class=\"todo\">{}</span>", s)
                     }
-                    _ => panic!("{}", e),
-                }),
-            ))),
-            e => Some(e),
+                }
+                _ => {}
+            }
+            kept.push(items[i].clone());
+            i += 1;
+        }
+        (kept, captions)
+    }
+}
+
+/// Inserts a numbered `<caption>` at the start of each captioned table.
+struct CaptionMiddleware<'a, I> {
+    iter: I,
+    captions: std::vec::IntoIter<Option<TableCaption>>,
+    number: usize,
+    pending: Option<Event<'a>>,
+}
+
+impl<'a, I> CaptionMiddleware<'a, I>
+where
+    I: Iterator<Item = Event<'a>>,
+{
+    fn new(iter: I, captions: Vec<Option<TableCaption>>) -> Self {
+        CaptionMiddleware {
+            iter,
+            captions: captions.into_iter(),
+            number: 0,
+            pending: None,
+        }
+    }
+}
+
+impl<'a, I> Iterator for CaptionMiddleware<'a, I>
+where
+    I: Iterator<Item = Event<'a>>,
+{
+    type Item = Event<'a>;
+
+    fn next(&mut self) -> Option<Event<'a>> {
+        if let Some(event) = self.pending.take() {
+            return Some(event);
+        }
+        let event = self.iter.next()?;
+        if let Event::Start(Tag::Table(_)) = event {
+            if let Some(Some(caption)) = self.captions.next() {
+                self.number += 1;
+                let mut html = String::from("<caption");
+                if let Some(label) = &caption.label {
+                    html.push_str(" id=\"");
+                    escape_html(&mut html, label).unwrap();
+                    html.push('"');
+                }
+                html.push_str(&format!(">Table {}: ", self.number));
+                escape_html(&mut html, &caption.text).unwrap();
+                html.push_str("</caption>");
+                self.pending = Some(Event::Html(html.into()));
+            }
         }
+        Some(event)
     }
 }
 
+/// Renders math with KaTeX, collecting a diagnostic for each formula that
+/// fails to render.
+struct KatexMiddleware<'s> {
+    markdown: &'s str,
+    output: katex::OutputType,
+    diagnostics: Vec<Diagnostic>,
+}
+
+impl<'s> KatexMiddleware<'s> {
+    fn new(markdown: &'s str, output: MathOutput) -> KatexMiddleware<'s> {
+        KatexMiddleware {
+            markdown,
+            output: match output {
+                MathOutput::HtmlAndMathml => katex::OutputType::HtmlAndMathml,
+                MathOutput::Html => katex::OutputType::Html,
+                MathOutput::Mathml => katex::OutputType::Mathml,
+            },
+            diagnostics: Vec::new(),
+        }
+    }
+
+    fn map<'a>(&mut self, event: Event<'a>, range: Range<usize>) -> Event<'a> {
+        let (text, display) = match event {
+            Event::DisplayMath(text) => (text, true),
+            Event::InlineMath(text) => (text, false),
+            e => return e,
+        };
+        let opts = katex::Opts::builder()
+            .display_mode(display)
+            .output_type(self.output)
+            .build()
+            .unwrap();
+        match katex::render_with_opts(&text, opts) {
+            Ok(html) => Event::Html(CowStr::from(html)),
+            Err(e) => {
+                let message = match e {
+                    katex::Error::JsExecError(s) => katex_message(&s),
+                    e => e.to_string(),
+                };
+                let mut html = String::from(if display { "<div" } else { "<span" });
+                html.push_str(" class=\"math-error\" title=\"");
+                escape_html(&mut html, &message).unwrap();
+                html.push_str("\">");
+                escape_html(&mut html, &text).unwrap();
+                html.push_str(if display { "</div>" } else { "</span>" });
+                self.diagnostics
+                    .push(Diagnostic::new(self.markdown, range, &text, message));
+                Event::Html(CowStr::from(html))
+            }
+        }
+    }
+}
+
+/// Extracts a readable message from a KaTeX exception, which is wrapped in
+/// a debug-formatted string and followed by the formula with the position
+/// of the error underlined.
+fn katex_message(exception: &str) -> String {
+    let message = exception
+        .find("String(\"")
+        .map_or(exception, |start| &exception[start + 8..]);
+    let message = message.split("\\n").next().unwrap();
+    let message = message.strip_prefix("ParseError: ").unwrap_or(message);
+    message
+        .trim_end_matches(['"', ')', ':', ' '])
+        .replace("\\\\", "\\")
+        .replace("\\\"", "\"")
+}
+
 impl<T: AsRef<str>> Markdown<T> {
     pub fn render_tex(&self) -> String {
-        // Compile regular expressions to match arXiv and DOI references
-        let new_arxiv = Regex::new(r"^ar[xX]iv:([0-9]{4}[.][0-9]{4,}(v[0-9]+)?)$").unwrap();
-        let old_arxiv = Regex::new(r"^(ar[xX]iv:)?([a-zA-Z.-]+/[0-9]{7}(v[0-9]+)?)$").unwrap();
-        let doi = Regex::new(r"^(doi:)?(10[.][0-9.]+/[0-9a-zA-Z()._-]+)$").unwrap();
-
-        let mut reference_callback = |link: pulldown_cmark::BrokenLink| {
-            let reference = link.reference;
-            if let Some(c) = new_arxiv.captures(&reference) {
-                Some((
-                    format!("https://arxiv.org/abs/{}", c.get(1).unwrap().as_str()),
-                    format!("arXiv:{}", c.get(1).unwrap().as_str()),
-                ))
-            } else if let Some(c) = old_arxiv.captures(&reference) {
-                Some((
-                    format!("https://arxiv.org/abs/{}", c.get(2).unwrap().as_str()),
-                    format!("{}", c.get(2).unwrap().as_str()),
-                ))
-            } else if let Some(c) = doi.captures(&reference) {
-                Some((
-                    format!("https://dx.doi.org/{}", c.get(2).unwrap().as_str()),
-                    format!("doi:{}", c.get(2).unwrap().as_str()),
-                ))
+        self.render_tex_with(&TexOptions::default()).0
+    }
+
+    /// Render to latex, returning warnings about any content that could
+    /// not be translated alongside the latex.
+    pub fn render_tex_with(&self, opts: &TexOptions) -> (String, Vec<String>) {
+        let mut latex = String::new();
+        let (events, mut warnings) =
+            self.events_with_warnings(opts.data_dir.as_deref(), opts.note_id.as_deref());
+        warnings.extend(LatexWriter::new(events, &mut latex, opts).run().unwrap());
+
+        (latex, warnings)
+    }
+
+    pub fn render_typst(&self) -> String {
+        self.render_typst_with(&TypstOptions::default()).0
+    }
+
+    /// Render to typst, returning warnings about any content that could
+    /// not be translated alongside the typst.
+    pub fn render_typst_with(&self, opts: &TypstOptions) -> (String, Vec<String>) {
+        let mut typst = String::new();
+        let (events, mut warnings) =
+            self.events_with_warnings(opts.data_dir.as_deref(), opts.note_id.as_deref());
+        warnings.extend(
+            typst::TypstWriter::new(events, &mut typst, opts)
+                .run()
+                .unwrap(),
+        );
+
+        (typst, warnings)
+    }
+
+    /// Text of a level 1 heading at the start, if there is one.
+    pub(crate) fn title(&self) -> Option<String> {
+        take_title(&mut self.parser().collect())
+    }
+
+    /// Text of the first paragraph, without formatting, shortened to about
+    /// `length` characters.
+    pub(crate) fn summary(&self, length: usize) -> Option<String> {
+        let mut events = self
+            .parser()
+            .skip_while(|event| !matches!(event, Event::Start(Tag::Paragraph)));
+        events.next()?;
+        let mut summary = String::new();
+        for event in events {
+            match event {
+                Event::Text(text) | Event::Code(text) | Event::InlineMath(text) => {
+                    summary.push_str(&text)
+                }
+                Event::SoftBreak | Event::HardBreak => summary.push(' '),
+                Event::End(TagEnd::Paragraph) => break,
+                _ => {}
+            }
+        }
+        if summary.chars().count() > length {
+            // Break at the last space that fits
+            let end = summary
+                .char_indices()
+                .nth(length)
+                .map_or(summary.len(), |(i, _)| i);
+            let end = if summary[end..].starts_with(' ') {
+                end
             } else {
-                None
+                summary[..end].rfind(' ').unwrap_or(end)
+            };
+            summary.truncate(end);
+            summary.push('…');
+        }
+        Some(summary)
+    }
+
+    /// The headings, in order, with the IDs they are given in html.
+    /// Transcluded notes are read from `data_dir`, as when rendering the
+    /// note with the ID `id`.
+    pub(crate) fn outline(&self, data_dir: Option<&Path>, id: Option<&str>) -> Vec<TocEntry> {
+        let mut events = self.events(data_dir, id, &mut Vec::new());
+        toc::heading_ids(&mut events, |(e, _)| e, |(e, _)| e)
+    }
+
+    /// Destinations of the images, including those in transcluded notes,
+    /// which are read from `data_dir` as when rendering the note `id`.
+    pub(crate) fn images(&self, data_dir: Option<&Path>, id: Option<&str>) -> Vec<String> {
+        let mut images: Vec<String> = Vec::new();
+        for (event, _) in self.events(data_dir, id, &mut Vec::new()) {
+            if let Event::Start(Tag::Image { dest_url, .. }) = event {
+                if !images.iter().any(|image| *image == *dest_url) {
+                    images.push(dest_url.to_string());
+                }
             }
-            .map(|(url, label)| {
-                (url.into(), label.into()) as (pulldown_cmark::CowStr, pulldown_cmark::CowStr)
-            })
+        }
+        images
+    }
+
+    /// IDs of the other notes that are linked to, in order of first
+    /// appearance.
+    pub(crate) fn note_links(&self) -> Vec<String> {
+        let mut links: Vec<String> = Vec::new();
+        for event in self.parser() {
+            if let Event::Start(Tag::Link { dest_url, .. }) = event {
+                if let Some(id) = note_link(&dest_url) {
+                    if !links.iter().any(|link| link == id) {
+                        links.push(id.to_string());
+                    }
+                }
+            }
+        }
+        links
+    }
+}
+
+/// Render several notes as chapters (or sections, if `chapters` is false)
+/// of a single latex document. Links between the notes become references
+/// to the corresponding chapter, and all arXiv and DOI references are
+/// collected in one bibliography.
+pub(crate) fn render_tex_book<T: AsRef<str>>(
+    book: &[TexChapter<T>],
+    opts: &TexOptions,
+    chapters: bool,
+) -> (String, Vec<String>) {
+    let opts = TexOptions {
+        bibliography: true,
+        ..opts.clone()
+    };
+    let mut latex = String::new();
+    let mut warnings = Vec::new();
+    let mut citations = Vec::new();
+
+    let mut chapter_events: Vec<Vec<Event>> = book
+        .iter()
+        .map(|chapter| {
+            let (events, data_warnings) = chapter
+                .body
+                .events_with_warnings(opts.data_dir.as_deref(), Some(chapter.id));
+            warnings.extend(data_warnings);
+            events
+        })
+        .collect();
+    let notes: HashMap<&str, HashSet<String>> = book
+        .iter()
+        .zip(&mut chapter_events)
+        .map(|(chapter, events)| {
+            let headings = toc::heading_ids(events, |e| e, |e| e);
+            (
+                chapter.id,
+                headings.into_iter().map(|entry| entry.id).collect(),
+            )
+        })
+        .collect();
+
+    if !opts.fragment {
+        let mut writer = LatexWriter::new(Vec::new(), &mut latex, &opts);
+        writer.plots = chapter_events.iter().any(|events| has_plots(events, &opts));
+        writer
+            .write_preamble(if chapters { "report" } else { "article" })
+            .unwrap();
+    }
+    if opts.toc {
+        latex.push_str("\\tableofcontents\n");
+    }
+    for (chapter, mut events) in book.iter().zip(chapter_events) {
+        let title = match chapter.title {
+            Some(title) => title.to_string(),
+            None => take_title(&mut events).unwrap_or_else(|| chapter.id.to_string()),
         };
 
-        // Generate raw HTML
-        let parser = Parser::new_with_broken_link_callback(
-            self.0.as_ref(),
-            Options::all(),
-            Some(&mut reference_callback),
-        );
+        let mut writer = LatexWriter::new(events, &mut latex, &opts);
+        writer.notes = Some(&notes);
+        writer.citations = citations;
+        writer.heading_offset = if chapters { 0 } else { 1 };
+        writer.label_prefix = format!("sec:{}:", chapter.id);
+        writer.write_chapter(chapters, chapter.id, &title).unwrap();
+        writer.write_body().unwrap();
+        citations = writer.citations;
+        warnings.extend(writer.warnings);
+    }
 
-        let mut latex = String::new();
-        push_latex(&mut latex, parser);
+    let mut writer = LatexWriter::new(Vec::new(), &mut latex, &opts);
+    writer.citations = citations;
+    writer.write_bibliography().unwrap();
+    if !opts.fragment {
+        writer.write("\\end{document}\n").unwrap();
+    }
+    (latex, warnings)
+}
+
+/// Replaces each code block that `is_block` picks out, such as a Graphviz
+/// diagram or a plot, with the html that `render` makes from its source.
+/// Blocks that fail to render are left as code, with a diagnostic.
+fn render_blocks<'a>(
+    events: Vec<(Event<'a>, Range<usize>)>,
+    markdown: &str,
+    diagnostics: &mut Vec<Diagnostic>,
+    is_block: fn(&CodeBlockKind) -> bool,
+    render: impl Fn(&str) -> Result<String, String>,
+) -> Vec<(Event<'a>, Range<usize>)> {
+    let mut rendered = Vec::with_capacity(events.len());
+    let mut events = events.into_iter();
+    while let Some((event, range)) = events.next() {
+        let Event::Start(Tag::CodeBlock(kind)) = &event else {
+            rendered.push((event, range));
+            continue;
+        };
+        if !is_block(kind) {
+            rendered.push((event, range));
+            continue;
+        }
 
-        latex
+        let mut block = vec![(event, range.clone())];
+        let mut source = String::new();
+        for (event, range) in events.by_ref() {
+            if let Event::Text(text) = &event {
+                source.push_str(text);
+            }
+            let end = matches!(event, Event::End(TagEnd::CodeBlock));
+            block.push((event, range));
+            if end {
+                break;
+            }
+        }
+        match render(&source) {
+            Ok(html) => rendered.push((Event::Html(CowStr::from(html)), range)),
+            Err(message) => {
+                let first_line = source.lines().next().unwrap_or_default();
+                diagnostics.push(Diagnostic::new(markdown, range, first_line, message));
+                rendered.extend(block);
+            }
+        }
     }
+    rendered
 }
 
-fn push_latex<'a, I>(s: &mut String, iter: I)
-where
-    I: Iterator<Item = Event<'a>>,
-{
-    LatexWriter::new(iter, s).run().unwrap();
+/// Whether there are plots that will be drawn with pgfplots, rather than
+/// written as code for want of a directory to draw them in.
+fn has_plots(events: &[Event], opts: &TexOptions) -> bool {
+    opts.diagram_dir.is_some()
+        && events
+            .iter()
+            .any(|event| matches!(event, Event::Start(Tag::CodeBlock(kind)) if plot::is_plot(kind)))
 }
 
-struct LatexWriter<'a, I, W> {
+/// Removes a level 1 heading from the start of a note, returning its text.
+fn take_title(events: &mut Vec<Event>) -> Option<String> {
+    // Skip over any front matter
+    let start = match events.first() {
+        Some(Event::Start(Tag::MetadataBlock(_))) => {
+            events
+                .iter()
+                .position(|e| matches!(e, Event::End(TagEnd::MetadataBlock(_))))?
+                + 1
+        }
+        _ => 0,
+    };
+    if !matches!(
+        events.get(start),
+        Some(Event::Start(Tag::Heading {
+            level: HeadingLevel::H1,
+            ..
+        }))
+    ) {
+        return None;
+    }
+    let end = start
+        + events[start..]
+            .iter()
+            .position(|e| matches!(e, Event::End(TagEnd::Heading(_))))?;
+    let mut title = String::new();
+    for event in events.drain(start..=end) {
+        match event {
+            Event::Text(text) | Event::Code(text) => title.push_str(&text),
+            Event::InlineMath(text) => {
+                title.push('$');
+                title.push_str(&text);
+                title.push('$');
+            }
+            _ => {}
+        }
+    }
+    Some(title)
+}
+
+/// Returns the ID of the note that a link points to, if it is a link to
+/// another note, such as `other-note`, `/other-note` or `other-note.md`.
+pub(crate) fn note_link(dest: &str) -> Option<&str> {
+    let dest = dest.split('#').next().unwrap();
+    let dest = dest
+        .strip_prefix("./")
+        .or_else(|| dest.strip_prefix('/'))
+        .unwrap_or(dest);
+    let id = dest.strip_suffix(".md").unwrap_or(dest);
+    crate::NoteID::try_from(id).ok().map(|_| id)
+}
+
+/// An arXiv or DOI reference cited in a document.
+struct Citation {
+    key: String,
+    url: String,
+    label: String,
+}
+
+/// Removes footnote definitions from the event stream, so that they can be
+/// written at the site of their first reference.
+#[allow(clippy::type_complexity)]
+fn extract_footnotes(
+    events: Vec<Event<'_>>,
+) -> (Vec<Event<'_>>, HashMap<CowStr<'_>, Vec<Event<'_>>>) {
+    let mut kept = Vec::with_capacity(events.len());
+    let mut footnotes = HashMap::new();
+    let mut events = events.into_iter();
+    while let Some(event) = events.next() {
+        if let Event::Start(Tag::FootnoteDefinition(name)) = event {
+            let mut definition = Vec::new();
+            let mut nest = 0;
+            for event in events.by_ref() {
+                match event {
+                    Event::Start(Tag::FootnoteDefinition(_)) => nest += 1,
+                    Event::End(TagEnd::FootnoteDefinition) if nest == 0 => break,
+                    Event::End(TagEnd::FootnoteDefinition) => nest -= 1,
+                    _ => {}
+                }
+                definition.push(event);
+            }
+            footnotes.insert(name, definition);
+        } else {
+            kept.push(event);
+        }
+    }
+    (kept, footnotes)
+}
+
+struct LatexWriter<'a, 'o, W> {
     /// Iterator supplying events.
-    iter: I,
+    iter: std::vec::IntoIter<Event<'a>>,
 
     /// Writer to write to.
     writer: W,
 
+    /// Options controlling the output.
+    opts: &'o TexOptions,
+
     /// Whether or not the last write wrote a newline.
     end_newline: bool,
 
+    /// Captions of the tables in the document, in order.
+    captions: std::vec::IntoIter<Option<TableCaption>>,
+    table_captioned: bool,
+    in_table: bool,
+
     table_cells: usize,
     table_cell_index: usize,
     numbers: HashMap<CowStr<'a>, usize>,
+
+    /// Footnote definitions that have not yet been referenced.
+    footnotes: HashMap<CowStr<'a>, Vec<Event<'a>>>,
+    deferred_footnotes: Vec<(usize, Vec<Event<'a>>)>,
+    in_footnote: bool,
+
+    /// Html elements that are currently open, with the latex to close them.
+    html_stack: Vec<(String, &'static str)>,
+    in_html_comment: bool,
+    /// Whether nothing has been written in the current paragraph (or html
+    /// block) yet, so a `<br>` would have no line to end.
+    paragraph_empty: bool,
+
+    /// Warnings about content that could not be translated.
+    warnings: Vec<String>,
+
+    /// IDs of the notes in the document, with the IDs of their headings,
+    /// which links can refer to.
+    notes: Option<&'o HashMap<&'o str, HashSet<String>>>,
+    /// Number of levels to demote headings by.
+    heading_offset: usize,
+    /// Prefix of the labels of headings, followed by their IDs.
+    label_prefix: String,
+    /// IDs of the headings, and labels of the tables, which links within
+    /// the note can refer to.
+    heading_ids: HashSet<String>,
+    table_labels: HashSet<String>,
+    /// Label of the heading being written.
+    heading_label: Option<String>,
+    /// Whether to list unnumbered headings in the table of contents.
+    contents: bool,
+    /// Line adding the heading being written to the table of contents.
+    heading_contents: Option<String>,
+    /// Latex to write at the end of each open link.
+    link_ends: Vec<String>,
+    /// References cited so far, in order.
+    citations: Vec<Citation>,
+    /// Whether the document has plots to draw with pgfplots.
+    plots: bool,
 }
 
-impl<'a, I, W> LatexWriter<'a, I, W>
+impl<'a, 'o, W> LatexWriter<'a, 'o, W>
 where
-    I: Iterator<Item = Event<'a>>,
     W: pulldown_cmark_escape::StrWrite,
 {
-    fn new(iter: I, writer: W) -> Self {
+    fn new(mut events: Vec<Event<'a>>, writer: W, opts: &'o TexOptions) -> Self {
+        let headings = toc::heading_ids(&mut events, |e| e, |e| e);
+        let contents = opts.toc || toc::has_marker(&events, |e| e);
+        let (events, captions) = TableCaption::extract(events, |e| e);
+        let table_labels = captions
+            .iter()
+            .flatten()
+            .filter_map(|caption| caption.label.clone())
+            .collect();
+        let (events, footnotes) = extract_footnotes(events);
+        let plots = has_plots(&events, opts);
         Self {
-            iter,
+            iter: events.into_iter(),
             writer,
+            opts,
             end_newline: true,
+            captions: captions.into_iter(),
+            table_captioned: false,
+            in_table: false,
             table_cells: 0,
             table_cell_index: 0,
             numbers: HashMap::new(),
+            footnotes,
+            deferred_footnotes: Vec::new(),
+            in_footnote: false,
+            html_stack: Vec::new(),
+            in_html_comment: false,
+            paragraph_empty: false,
+            warnings: Vec::new(),
+            notes: None,
+            heading_offset: 0,
+            label_prefix: "sec:".to_string(),
+            heading_ids: headings.into_iter().map(|entry| entry.id).collect(),
+            table_labels,
+            heading_label: None,
+            contents,
+            heading_contents: None,
+            link_ends: Vec::new(),
+            citations: Vec::new(),
+            plots,
         }
     }
 
@@ -194,84 +1049,230 @@ where
         Ok(())
     }
 
-    pub fn run(mut self) -> Result<(), W::Error> {
-        self.write("\\documentclass{article}\n\n")?;
+    /// Writes the document, returning warnings about any content that
+    /// could not be translated.
+    pub fn run(mut self) -> Result<Vec<String>, W::Error> {
+        if !self.opts.fragment {
+            self.write_preamble("article")?;
+        }
+        if self.opts.toc {
+            self.write("\\tableofcontents\n")?;
+        }
+        self.write_body()?;
+        self.write_bibliography()?;
+        if !self.opts.fragment {
+            self.write("\\end{document}\n")?;
+        }
+        Ok(self.warnings)
+    }
+
+    /// Writes the preamble, up to and including `\begin{document}`.
+    fn write_preamble(&mut self, class: &str) -> Result<(), W::Error> {
+        write!(&mut self.writer, "\\documentclass{{{}}}\n\n", class)?;
         self.write("\\usepackage[normalem]{ulem}\n")?;
         self.write("\\usepackage{minted}\n")?;
         self.write("\\usepackage{graphicx}\n")?;
+        if self.plots {
+            self.write("\\usepackage{pgfplots}\n")?;
+            self.write("\\pgfplotsset{compat=1.16}\n")?;
+        }
         self.write("\\usepackage{hyperref}\n")?;
         self.write("\\usepackage[a4paper]{geometry}\n")?;
+        if self.opts.booktabs {
+            self.write("\\usepackage{booktabs}\n")?;
+        }
+        if self.opts.longtable {
+            self.write("\\usepackage{longtable}\n")?;
+        }
         self.write("\\usepackage{amsmath}\n\n")?;
         self.write("\\setcounter{tocdepth}{6}\n")?;
         self.write("\\setcounter{secnumdepth}{6}\n\n")?;
-        self.write("\\begin{document}\n")?;
+        self.write("\\begin{document}\n")
+    }
+
+    /// Writes the body of the document from the events.
+    fn write_body(&mut self) -> Result<(), W::Error> {
         while let Some(event) = self.iter.next() {
-            match event {
-                Event::Start(tag) => {
-                    self.start_tag(tag)?;
-                }
-                Event::End(tag) => {
-                    self.end_tag(tag)?;
-                }
-                Event::Text(text) => {
-                    self.write(&text)?;
-                    self.end_newline = text.ends_with('\n');
-                }
-                Event::Code(text) => {
-                    self.write(r"\mintinline{text}{")?;
-                    self.write(&text)?;
-                    self.write(r"}")?;
-                }
-                Event::InlineMath(text) => {
-                    self.write(r"$")?;
-                    self.write(&text)?;
-                    self.write(r"$")?;
-                }
-                Event::DisplayMath(text) => {
-                    self.write("\\begin{align*}")?;
-                    self.write(&text)?;
-                    self.write("\\end{align*}")?;
-                }
-                Event::Html(html) => {
-                    self.write("\\begin{verbatim}")?;
-                    self.write(&html)?;
-                    self.write("\\end{verbatim}")?;
-                }
-                Event::InlineHtml(text) => {
-                    self.write(r"\mintinline{text}{")?;
+            self.event(event)?;
+        }
+        while let Some((_, close)) = self.html_stack.pop() {
+            self.write(close)?;
+        }
+        if !self.end_newline {
+            self.write_newline()?;
+        }
+        Ok(())
+    }
+
+    /// Writes the heading that starts a note in a book.
+    fn write_chapter(&mut self, chapters: bool, id: &str, title: &str) -> Result<(), W::Error> {
+        if !self.end_newline {
+            self.write_newline()?;
+        }
+        self.write(if chapters { "\\chapter{" } else { "\\section{" })?;
+        escape_latex(&mut self.writer, title)?;
+        write!(&mut self.writer, "}}\\label{{note:{}}}", id)?;
+        self.write_newline()
+    }
+
+    /// Writes the list of cited references, if any.
+    fn write_bibliography(&mut self) -> Result<(), W::Error> {
+        if self.citations.is_empty() {
+            return Ok(());
+        }
+        if !self.end_newline {
+            self.write_newline()?;
+        }
+        self.write("\\begin{thebibliography}{99}\n")?;
+        for i in 0..self.citations.len() {
+            let Citation { key, url, label } = &self.citations[i];
+            write!(&mut self.writer, "\\bibitem{{{}}} \\href{{", key)?;
+            escape_href(&mut self.writer, url)?;
+            self.writer.write_str("}{")?;
+            escape_latex(&mut self.writer, label)?;
+            self.writer.write_str("}\n")?;
+        }
+        self.write("\\end{thebibliography}\n")
+    }
+
+    /// Records a citation of a reference, returning its key.
+    fn cite(&mut self, url: &str, label: &str) -> String {
+        let key: String = label
+            .chars()
+            .map(|c| match c {
+                'a'..='z' | 'A'..='Z' | '0'..='9' | ':' | '.' | '/' | '-' => c,
+                _ => '-',
+            })
+            .collect();
+        if !self.citations.iter().any(|c| c.key == key) {
+            self.citations.push(Citation {
+                key: key.clone(),
+                url: url.to_string(),
+                label: label.to_string(),
+            });
+        }
+        key
+    }
+
+    /// Writes a single event.
+    fn event(&mut self, event: Event<'a>) -> Result<(), W::Error> {
+        let paragraph_empty = std::mem::replace(
+            &mut self.paragraph_empty,
+            matches!(event, Event::Start(Tag::Paragraph | Tag::HtmlBlock)),
+        );
+        match event {
+            Event::Start(tag) => {
+                self.start_tag(tag)?;
+            }
+            Event::End(tag) => {
+                self.end_tag(tag)?;
+            }
+            Event::Text(text) => {
+                if self.in_table {
+                    escape_latex(&mut self.writer, &text)?;
+                } else {
                     self.write(&text)?;
-                    self.write(r"}")?;
-                }
-                Event::SoftBreak => {
-                    self.write_newline()?;
                 }
-                Event::HardBreak => {
-                    self.write(r"\\")?;
+                self.end_newline = text.ends_with('\n');
+            }
+            Event::Code(text) => {
+                self.write(r"\mintinline{text}{")?;
+                self.write(&text)?;
+                self.write(r"}")?;
+            }
+            Event::InlineMath(text) => {
+                self.write(r"$")?;
+                self.write(&text)?;
+                self.write(r"$")?;
+            }
+            Event::DisplayMath(text) => {
+                if !self.end_newline {
                     self.write_newline()?;
                 }
-                Event::Rule => {
-                    if self.end_newline {
-                        self.write("\\hline\n")?;
-                    } else {
-                        self.write("\n\\hline\n")?;
+                match display_math_environment(&text) {
+                    Some(env) => {
+                        writeln!(&mut self.writer, "\\begin{{{}}}", env)?;
+                        self.write(text.trim())?;
+                        write!(&mut self.writer, "\n\\end{{{}}}\n", env)?;
+                    }
+                    None => {
+                        self.write(text.trim())?;
+                        self.write_newline()?;
                     }
                 }
-                Event::FootnoteReference(name) => {
-                    let len = self.numbers.len() + 1;
-                    self.write(r"\footnotemark[")?;
-                    let number = *self.numbers.entry(name).or_insert(len);
-                    write!(&mut self.writer, "{}", number)?;
-                    self.write("]")?;
-                }
-                Event::TaskListMarker(true) => {
-                    self.write(r"\makebox[0pt][l]{$\square$}\raisebox{.15ex}{\hspace{0.1em}$\checkmark$}\n")?;
+                self.end_newline = true;
+            }
+            Event::Html(html) | Event::InlineHtml(html) => {
+                self.paragraph_empty = paragraph_empty;
+                self.write_html(&html)?;
+            }
+            Event::SoftBreak => {
+                self.write_newline()?;
+            }
+            Event::HardBreak => {
+                self.write(r"\\")?;
+                self.write_newline()?;
+            }
+            Event::Rule => {
+                if self.end_newline {
+                    self.write("\\hline\n")?;
+                } else {
+                    self.write("\n\\hline\n")?;
                 }
-                Event::TaskListMarker(false) => {
-                    self.write(r"\makebox[0pt][l]{$\square$}\n")?;
+            }
+            Event::FootnoteReference(name) => {
+                let len = self.numbers.len() + 1;
+                let number = *self.numbers.entry(name.clone()).or_insert(len);
+                match self.footnotes.remove(&name) {
+                    // A `\footnote` can't be nested inside another, so mark it
+                    // here and write the text after the enclosing footnote
+                    Some(definition) if self.in_footnote => {
+                        write!(&mut self.writer, "\\footnotemark[{}]", number)?;
+                        self.deferred_footnotes.push((number, definition));
+                    }
+                    Some(definition) => {
+                        write!(&mut self.writer, "\\footnote[{}]{{", number)?;
+                        self.write_footnote(definition)?;
+                        self.write("}")?;
+                        while !self.deferred_footnotes.is_empty() {
+                            for (number, definition) in std::mem::take(&mut self.deferred_footnotes)
+                            {
+                                write!(&mut self.writer, "\\footnotetext[{}]{{", number)?;
+                                self.write_footnote(definition)?;
+                                self.write("}")?;
+                            }
+                        }
+                    }
+                    // Already written at an earlier reference, or never defined
+                    None => {
+                        write!(&mut self.writer, "\\footnotemark[{}]", number)?;
+                    }
                 }
             }
+            Event::TaskListMarker(true) => {
+                self.write(
+                    r"\makebox[0pt][l]{$\square$}\raisebox{.15ex}{\hspace{0.1em}$\checkmark$}\n",
+                )?;
+            }
+            Event::TaskListMarker(false) => {
+                self.write(r"\makebox[0pt][l]{$\square$}\n")?;
+            }
         }
-        self.write("\\end{document}\n")?;
+        Ok(())
+    }
+
+    /// Writes the body of a footnote definition.
+    fn write_footnote(&mut self, definition: Vec<Event<'a>>) -> Result<(), W::Error> {
+        let in_footnote = std::mem::replace(&mut self.in_footnote, true);
+        let mut events = definition.into_iter().peekable();
+        // Don't start a new paragraph at the beginning of the footnote
+        if let Some(Event::Start(Tag::Paragraph)) = events.peek() {
+            events.next();
+        }
+        for event in events {
+            self.event(event)?;
+        }
+        self.in_footnote = in_footnote;
         Ok(())
     }
 
@@ -279,43 +1280,101 @@ where
     fn start_tag(&mut self, tag: Tag<'a>) -> Result<(), W::Error> {
         match tag {
             Tag::Paragraph => {
+                if let Some(len) = toc::marker_len(self.iter.as_slice(), |e| e) {
+                    self.iter.nth(len - 1);
+                    if !self.end_newline {
+                        self.write_newline()?;
+                    }
+                    return self.write("\\tableofcontents\n");
+                }
                 if self.end_newline {
                     self.write("\n")
                 } else {
                     self.write("\n\n")
                 }
             }
-            Tag::Heading { level, .. } => {
-                use pulldown_cmark::HeadingLevel::{H1, H2, H3, H4, H5, H6};
-                let section = match level {
-                    H1 => "section*",
-                    H2 => "subsection*",
-                    H3 => "subsubsection*",
-                    H4 => "paragraph",
-                    H5 => "subparagraph",
-                    H6 => "subsubparagraph",
-                };
-                if self.end_newline {
-                    self.end_newline = false;
-                    write!(&mut self.writer, "\\{}{{", section)
-                } else {
-                    write!(&mut self.writer, "\n\\{}{{", section)
+            Tag::Heading {
+                level, id, classes, ..
+            } => {
+                const SECTIONS: &[&str] = &[
+                    "section",
+                    "subsection",
+                    "subsubsection",
+                    "paragraph",
+                    "subparagraph",
+                ];
+                let depth = level as usize - 1 + self.heading_offset;
+                let section = SECTIONS[depth.min(SECTIONS.len() - 1)];
+                // `{.unnumbered}` headings are starred
+                let unnumbered = classes.iter().any(|class| &**class == "unnumbered");
+                self.heading_label = id.map(|id| format!("{}{}", self.label_prefix, label(&id)));
+                if unnumbered && self.contents {
+                    // Starred headings are left out of the table of
+                    // contents unless added by hand, with the text of the
+                    // heading escaped and its math kept
+                    let mut line = format!("\\addcontentsline{{toc}}{{{}}}{{", section);
+                    for event in self.iter.as_slice() {
+                        match event {
+                            Event::Text(text) => escape_latex(&mut line, text).unwrap(),
+                            Event::InlineMath(math) => {
+                                line.push('$');
+                                line.push_str(math);
+                                line.push('$');
+                            }
+                            Event::Code(code) => {
+                                line.push_str("\\texttt{");
+                                escape_latex(&mut line, code).unwrap();
+                                line.push('}');
+                            }
+                            Event::End(TagEnd::Heading(_)) => break,
+                            _ => {}
+                        }
+                    }
+                    line.push('}');
+                    self.heading_contents = Some(line);
+                }
+                if !self.end_newline {
+                    self.write_newline()?;
                 }
+                self.end_newline = false;
+                let star = if unnumbered { "*" } else { "" };
+                write!(&mut self.writer, "\\{}{}{{", section, star)
             }
             Tag::Table(alignments) => {
                 if !self.end_newline {
                     self.write_newline()?;
                 }
-                self.write("\\begin{center}\n\\begin{tabular}{")?;
+                let caption = self.captions.next().flatten();
+                self.table_captioned = caption.is_some();
+                self.in_table = true;
+                if self.opts.longtable {
+                    self.write("\\begin{longtable}{")?;
+                } else if self.table_captioned {
+                    self.write("\\begin{table}[htbp]\n\\centering\n")?;
+                    self.write_caption(caption.as_ref().unwrap())?;
+                    self.write("\n\\begin{tabular}{")?;
+                } else {
+                    self.write("\\begin{center}\n\\begin{tabular}{")?;
+                }
                 for alignment in &alignments {
                     match alignment {
-                        &Alignment::Center => self.write("c")?,
-                        &Alignment::Right => self.write("r")?,
+                        Alignment::Center => self.write("c")?,
+                        Alignment::Right => self.write("r")?,
                         _ => self.write("l")?,
                     }
                 }
                 self.table_cells = alignments.len();
-                self.write("}\n")
+                self.write("}\n")?;
+                if self.opts.longtable {
+                    if let Some(caption) = &caption {
+                        self.write_caption(caption)?;
+                        self.write("\\\\\n")?;
+                    }
+                }
+                if self.opts.booktabs {
+                    self.write("\\toprule\n")?;
+                }
+                Ok(())
             }
             Tag::TableHead => {
                 self.table_cell_index = 0;
@@ -337,18 +1396,39 @@ where
                 if !self.end_newline {
                     self.write_newline()?;
                 }
-                match info {
-                    CodeBlockKind::Fenced(info) => {
-                        let lang = info.split(' ').next().unwrap();
-                        if lang.is_empty() {
-                            self.write("\\begin{minted}{text}\n")
-                        } else {
-                            self.write("\\begin{minted}{")?;
-                            escape_html(&mut self.writer, lang)?;
-                            self.write("}\n")
-                        }
+                if graphviz::is_diagram(&info) {
+                    return self.write_diagram();
+                }
+                if plot::is_plot(&info) {
+                    return self.write_plot();
+                }
+                let info = match &info {
+                    CodeBlockKind::Fenced(info) => CodeInfo::parse(info),
+                    CodeBlockKind::Indented => CodeInfo::default(),
+                };
+                self.write("\\begin{minted}")?;
+                let mut options = Vec::new();
+                if info.linenos {
+                    options.push("linenos".to_string());
+                }
+                if !info.hl_lines.is_empty() {
+                    let lines: Vec<String> = info
+                        .hl_lines
+                        .iter()
+                        .map(|range| format!("{}-{}", range.start(), range.end()))
+                        .collect();
+                    options.push(format!("highlightlines={{{}}}", lines.join(",")));
+                }
+                if !options.is_empty() {
+                    write!(&mut self.writer, "[{}]", options.join(", "))?;
+                }
+                match info.lang {
+                    Some(lang) => {
+                        self.write("{")?;
+                        escape_html(&mut self.writer, lang)?;
+                        self.write("}\n")
                     }
-                    CodeBlockKind::Indented => self.write("\\begin{minted}{text}\n"),
+                    None => self.write("{text}\n"),
                 }
             }
             Tag::List(Some(1)) => {
@@ -392,12 +1472,80 @@ where
             } => {
                 self.write("\\href{mailto:")?;
                 escape_href(&mut self.writer, &dest)?;
+                self.link_ends.push("}".to_string());
                 self.write("}{")
             }
+            // arXiv and DOI references resolved by `References`
+            Tag::Link {
+                link_type:
+                    link_type @ (LinkType::ShortcutUnknown
+                    | LinkType::CollapsedUnknown
+                    | LinkType::ReferenceUnknown),
+                dest_url: dest,
+                title,
+                ..
+            } if self.opts.bibliography => {
+                let key = self.cite(&dest, &title);
+                if link_type == LinkType::ReferenceUnknown {
+                    // Keep the link text, and cite after it
+                    self.link_ends.push(format!("~\\cite{{{}}}", key));
+                    Ok(())
+                } else {
+                    write!(&mut self.writer, "\\cite{{{}}}", key)?;
+                    self.consume_text()
+                }
+            }
             Tag::Link { dest_url: dest, .. } => {
-                self.write("\\href{")?;
-                escape_href(&mut self.writer, &dest)?;
-                self.write("}{")
+                let fragment = dest.split_once('#').map(|(_, fragment)| fragment);
+                let sections = note_link(&dest).and_then(|id| Some((id, self.notes?.get(id)?)));
+                match sections {
+                    Some((id, sections)) => match fragment {
+                        Some(fragment) if sections.contains(fragment) => write!(
+                            &mut self.writer,
+                            "\\hyperref[sec:{}:{}]{{",
+                            id,
+                            label(fragment)
+                        )?,
+                        // Refer to the note as a whole
+                        _ => {
+                            if fragment.is_some() {
+                                self.warnings
+                                    .push(format!("link to `{}` has no heading to refer to", dest));
+                            }
+                            write!(&mut self.writer, "\\hyperref[note:{}]{{", id)?
+                        }
+                    },
+                    // A heading or table in this note
+                    _ if dest.starts_with('#') => {
+                        let anchor = &dest[1..];
+                        if self.table_labels.contains(anchor) {
+                            write!(&mut self.writer, "\\hyperref[{}]{{", label(anchor))?;
+                        } else if self.heading_ids.contains(anchor) {
+                            write!(
+                                &mut self.writer,
+                                "\\hyperref[{}{}]{{",
+                                self.label_prefix,
+                                label(anchor)
+                            )?;
+                        } else {
+                            // Write the link text alone, since there is
+                            // nothing to refer to
+                            self.warnings.push(format!(
+                                "link to `{}` has no heading or table to refer to",
+                                dest
+                            ));
+                            self.link_ends.push(String::new());
+                            return Ok(());
+                        }
+                    }
+                    _ => {
+                        self.write("\\href{")?;
+                        escape_href(&mut self.writer, &dest)?;
+                        self.write("}{")?;
+                    }
+                }
+                self.link_ends.push("}".to_string());
+                Ok(())
             }
             Tag::Image { dest_url: dest, .. } => {
                 self.write("\\includegraphics{")?;
@@ -405,29 +1553,16 @@ where
                 self.write("}\n")?;
                 self.consume_text()
             }
-            Tag::FootnoteDefinition(name) => {
-                if self.end_newline {
-                    self.write("\\footnotetext[")?;
-                } else {
-                    self.write("\n\\footnotetext[")?;
-                }
-                let len = self.numbers.len() + 1;
-                let number = *self.numbers.entry(name).or_insert(len);
-                write!(&mut self.writer, "{}", number)?;
-                self.write("]{")
-            }
+            // Removed from the event stream by `extract_footnotes`
+            Tag::FootnoteDefinition(_) => self.consume_text(),
             Tag::HtmlBlock => {
                 if !self.end_newline {
                     self.write_newline()?;
                 }
-                self.write("\\begin{verbatim}\n")
-            }
-            Tag::MetadataBlock(_) => {
-                if !self.end_newline {
-                    self.write_newline()?;
-                }
-                self.write("\\begin{comment}\n")
+                Ok(())
             }
+            // Front matter is parsed into `NoteMetadata`
+            Tag::MetadataBlock(_) => self.consume_text(),
         }
     }
 
@@ -435,13 +1570,37 @@ where
         match tag {
             TagEnd::Paragraph => {}
             TagEnd::Heading { .. } => {
-                self.write("}\n")?;
+                self.write("}")?;
+                if let Some(label) = self.heading_label.take() {
+                    write!(&mut self.writer, "\\label{{{}}}", label)?;
+                }
+                if let Some(line) = self.heading_contents.take() {
+                    self.write(&line)?;
+                }
+                self.write("\n")?;
             }
             TagEnd::Table => {
-                self.write("\\end{tabular}\n\\end{center}\n")?;
+                self.in_table = false;
+                if self.opts.booktabs {
+                    self.write("\\bottomrule\n")?;
+                }
+                if self.opts.longtable {
+                    self.write("\\end{longtable}\n")?;
+                } else if self.table_captioned {
+                    self.write("\\end{tabular}\n\\end{table}\n")?;
+                } else {
+                    self.write("\\end{tabular}\n\\end{center}\n")?;
+                }
             }
             TagEnd::TableHead => {
-                self.write("\\hline\n")?;
+                if self.opts.booktabs {
+                    self.write("\\midrule\n")?;
+                } else {
+                    self.write("\\hline\n")?;
+                }
+                if self.opts.longtable {
+                    self.write("\\endhead\n")?;
+                }
             }
             TagEnd::TableRow => {}
             TagEnd::TableCell => {
@@ -476,27 +1635,242 @@ where
             TagEnd::Strikethrough => {
                 self.write("}")?;
             }
-            TagEnd::Link { .. } => {
-                self.write("}")?;
-            }
-            TagEnd::Image { .. } => (), // shouldn't happen, handled in start
-            TagEnd::FootnoteDefinition => {
-                self.write("}\n")?;
+            TagEnd::Link => {
+                let end = self.link_ends.pop().unwrap_or_default();
+                self.write(&end)?;
             }
+            TagEnd::Image => (), // shouldn't happen, handled in start
+            TagEnd::FootnoteDefinition => (), // handled in start
             TagEnd::HtmlBlock => {
-                self.write("\\end{verbatim}\n")?;
+                if !self.end_newline {
+                    self.write_newline()?;
+                }
+            }
+            TagEnd::MetadataBlock(_) => (), // handled in start
+        }
+        Ok(())
+    }
+
+    /// Translates a fragment of html into latex. Elements that have no
+    /// latex equivalent are written as comments.
+    fn write_html(&mut self, html: &str) -> Result<(), W::Error> {
+        static TAG: LazyLock<Regex> = LazyLock::new(|| {
+            Regex::new(r"^<(/?)([a-zA-Z][a-zA-Z0-9-]*)((?:\s[^>]*)?)/?>").unwrap()
+        });
+        let mut rest = html;
+        while !rest.is_empty() {
+            if self.in_html_comment {
+                let end = rest.find("-->").unwrap_or(rest.len());
+                self.write_comment(&rest[..end])?;
+                if end < rest.len() {
+                    self.in_html_comment = false;
+                    rest = &rest[end + 3..];
+                } else {
+                    rest = "";
+                }
+            } else if let Some(comment) = rest.strip_prefix("<!--") {
+                self.in_html_comment = true;
+                rest = comment;
+            } else if let Some(c) = TAG.captures(rest) {
+                let name = c.get(2).unwrap().as_str().to_ascii_lowercase();
+                if c.get(1).unwrap().as_str().is_empty() {
+                    self.open_html_tag(
+                        &name,
+                        c.get(3).unwrap().as_str(),
+                        c.get(0).unwrap().as_str(),
+                    )?;
+                } else {
+                    self.close_html_tag(&name, c.get(0).unwrap().as_str())?;
+                }
+                rest = &rest[c.get(0).unwrap().end()..];
+            } else {
+                let end = rest[1..].find('<').map_or(rest.len(), |i| i + 1);
+                escape_latex(&mut self.writer, &unescape_html(&rest[..end]))?;
+                self.end_newline = rest[..end].ends_with('\n');
+                if !rest[..end].trim().is_empty() {
+                    self.paragraph_empty = false;
+                }
+                rest = &rest[end..];
             }
-            TagEnd::MetadataBlock(_) => {
-                self.write("\\end{comment}\n")?;
+        }
+        Ok(())
+    }
+
+    fn open_html_tag(&mut self, name: &str, attrs: &str, raw: &str) -> Result<(), W::Error> {
+        let (open, close) = match name {
+            // There is no line to end at the start of a paragraph
+            "br" if self.paragraph_empty => return Ok(()),
+            "br" => return self.write("\\\\\n"),
+            "hr" => return self.write("\\par\\noindent\\rule{\\textwidth}{0.4pt}\\par\n"),
+            "img" => {
+                self.paragraph_empty = false;
+                return match html_attribute(attrs, "src") {
+                    Some(src) => {
+                        self.write("\\includegraphics{")?;
+                        escape_href(&mut self.writer, &src)?;
+                        self.write("}")
+                    }
+                    None => self.drop_html(name, raw),
+                };
             }
+            "a" => match html_attribute(attrs, "href") {
+                Some(href) => {
+                    self.write("\\href{")?;
+                    escape_href(&mut self.writer, &href)?;
+                    ("}{", "}")
+                }
+                None => ("", ""),
+            },
+            "sub" => ("\\textsubscript{", "}"),
+            "sup" => ("\\textsuperscript{", "}"),
+            "kbd" => ("\\fbox{\\texttt{", "}}"),
+            "u" | "ins" => ("\\uline{", "}"),
+            "s" | "del" | "strike" => ("\\sout{", "}"),
+            "em" | "i" | "cite" | "var" => ("\\emph{", "}"),
+            "strong" | "b" => ("\\textbf{", "}"),
+            "code" | "tt" | "samp" => ("\\texttt{", "}"),
+            "small" => ("{\\small ", "}"),
+            "q" => ("``", "''"),
+            "p" => ("\\par\n", "\\par\n"),
+            "center" => ("\\begin{center}\n", "\\end{center}\n"),
+            "details" | "blockquote" => ("\\begin{quote}\n", "\\end{quote}\n"),
+            "summary" => ("\\textbf{", "}\\par\n"),
+            "div" | "span" => ("", ""),
+            _ => return self.drop_html(name, raw),
+        };
+        self.write(open)?;
+        self.html_stack.push((name.to_string(), close));
+        Ok(())
+    }
+
+    fn close_html_tag(&mut self, name: &str, raw: &str) -> Result<(), W::Error> {
+        match self.html_stack.iter().rposition(|(open, _)| open == name) {
+            Some(index) => {
+                // Close any elements that were left open inside this one
+                while self.html_stack.len() > index {
+                    let (_, close) = self.html_stack.pop().unwrap();
+                    self.write(close)?;
+                }
+                Ok(())
+            }
+            None if matches!(name, "br" | "hr" | "img") => Ok(()),
+            None => self.drop_html(name, raw),
+        }
+    }
+
+    /// Writes an html tag with no latex equivalent as a comment.
+    fn drop_html(&mut self, name: &str, raw: &str) -> Result<(), W::Error> {
+        let warning = format!("dropped unsupported html element <{}>", name);
+        if !self.warnings.contains(&warning) {
+            self.warnings.push(warning);
+        }
+        self.write_comment(raw)
+    }
+
+    /// Writes each line of `text` as a latex comment.
+    fn write_comment(&mut self, text: &str) -> Result<(), W::Error> {
+        for line in text.lines().filter(|line| !line.trim().is_empty()) {
+            self.write("%")?;
+            self.write(line)?;
+            self.write_newline()?;
         }
         Ok(())
     }
 
-    // run raw text, consuming end tag
+    /// Writes a Graphviz diagram as a figure, rendering it to pdf in the
+    /// diagram directory. Diagrams that can't be rendered are written as
+    /// code instead.
+    fn write_diagram(&mut self) -> Result<(), W::Error> {
+        self.write_figure("diagram", "includegraphics", "dot", |source, dir| {
+            graphviz::render_pdf(source, dir)
+        })
+    }
+
+    /// Writes a plot as a figure, drawn with pgfplots in a file in the
+    /// diagram directory. Plots that can't be drawn are written as code
+    /// instead.
+    fn write_plot(&mut self) -> Result<(), W::Error> {
+        let data_dir = self.opts.data_dir.clone().unwrap_or_default();
+        self.write_figure("plot", "input", "yaml", |source, dir| {
+            plot::render_pgf(source, &data_dir, dir)
+        })
+    }
+
+    /// Writes the code block being read as a figure, which `render` draws
+    /// to a file in the diagram directory, and `command` includes. Blocks
+    /// that can't be rendered are written as code in `language` instead,
+    /// with a warning about the `kind` of block.
+    fn write_figure(
+        &mut self,
+        kind: &str,
+        command: &str,
+        language: &str,
+        render: impl FnOnce(&str, &Path) -> Result<PathBuf, String>,
+    ) -> Result<(), W::Error> {
+        let mut source = String::new();
+        for event in self.iter.by_ref() {
+            match event {
+                Event::Text(text) => source.push_str(&text),
+                Event::End(TagEnd::CodeBlock) => break,
+                _ => {}
+            }
+        }
+        let rendered = match &self.opts.diagram_dir {
+            Some(dir) => render(&source, dir),
+            None => Err("no directory was given to render it to".to_string()),
+        };
+        match rendered {
+            Ok(path) => {
+                let path = self.include_path(path);
+                write!(
+                    &mut self.writer,
+                    "\\begin{{figure}}[htbp]\n\\centering\n\\{}{{",
+                    command
+                )?;
+                escape_href(&mut self.writer, &path.to_string_lossy())?;
+                self.write("}\n\\end{figure}\n")
+            }
+            Err(message) => {
+                self.warnings
+                    .push(format!("{} could not be rendered: {}", kind, message));
+                writeln!(&mut self.writer, "\\begin{{minted}}{{{}}}", language)?;
+                self.write(&source)?;
+                if !self.end_newline {
+                    self.write_newline()?;
+                }
+                self.write("\\end{minted}\n")
+            }
+        }
+    }
+
+    /// Path that the latex includes a rendered diagram or plot from.
+    fn include_path(&self, path: PathBuf) -> PathBuf {
+        match (&self.opts.diagram_path, path.file_name()) {
+            (Some(dir), Some(name)) => dir.join(name),
+            _ => path,
+        }
+    }
+
+    /// Writes the `\caption` and `\label` of a table.
+    fn write_caption(&mut self, caption: &TableCaption) -> Result<(), W::Error> {
+        self.write("\\caption{")?;
+        escape_latex(&mut self.writer, &caption.text)?;
+        self.write("}")?;
+        if let Some(caption_label) = &caption.label {
+            self.write("\\label{")?;
+            self.write(&label(caption_label))?;
+            self.write("}")?;
+        }
+        Ok(())
+    }
+
+    // run raw text, consuming end tag. Footnotes referenced from the text,
+    // such as an image's alt text, are written after it, since their
+    // definitions would otherwise never be written
     fn consume_text(&mut self) -> Result<(), W::Error> {
+        let mut references = Vec::new();
         let mut nest = 0;
-        while let Some(event) = self.iter.next() {
+        for event in self.iter.by_ref() {
             match event {
                 Event::Start(_) => nest += 1,
                 Event::End(_) => {
@@ -512,14 +1886,325 @@ where
                 | Event::Code(_)
                 | Event::Text(_) => {}
                 Event::SoftBreak | Event::HardBreak | Event::Rule => {}
-                Event::FootnoteReference(name) => {
-                    let len = self.numbers.len() + 1;
-                    let _r = *self.numbers.entry(name).or_insert(len);
-                }
+                Event::FootnoteReference(name) => references.push(name),
                 Event::TaskListMarker(true) => {}
                 Event::TaskListMarker(false) => {}
             }
         }
+        for name in references {
+            self.event(Event::FootnoteReference(name))?;
+        }
         Ok(())
     }
 }
+
+/// Chooses the environment to wrap display math in. Returns `None` if the
+/// math is already a complete top-level environment, such as `equation` or
+/// `align`, which should be written as is. Otherwise math that is split
+/// over multiple lines by `\\` goes in `align*`, and anything else in
+/// `equation*`.
+fn display_math_environment(math: &str) -> Option<&'static str> {
+    const TOP_LEVEL: &[&str] = &[
+        "equation", "align", "gather", "multline", "flalign", "alignat", "eqnarray",
+    ];
+    static ENVIRONMENT: LazyLock<Regex> =
+        LazyLock::new(|| Regex::new(r"^\\begin\{([a-zA-Z]+)\*?\}").unwrap());
+    static BEGIN: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"^\\begin\{[^}]*\}").unwrap());
+    static END: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"^\\end\{[^}]*\}").unwrap());
+    let math = math.trim();
+    if let Some(c) = ENVIRONMENT.captures(math) {
+        let env = &math[c.get(0).unwrap().range()];
+        let end = env.replacen("\\begin", "\\end", 1);
+        if TOP_LEVEL.contains(&&c[1]) && math.ends_with(&end) {
+            return None;
+        }
+    }
+
+    // Look for a line break that isn't inside a group or environment
+    let mut depth = 0usize;
+    let mut i = 0;
+    while i < math.len() {
+        let rest = &math[i..];
+        if let Some(m) = BEGIN.find(rest) {
+            depth += 1;
+            i += m.end();
+        } else if let Some(m) = END.find(rest) {
+            depth = depth.saturating_sub(1);
+            i += m.end();
+        } else if rest.starts_with("\\\\") {
+            if depth == 0 {
+                return Some("align*");
+            }
+            i += 2;
+        } else if let Some(escaped) = rest.strip_prefix('\\') {
+            // Skip escaped characters, such as `\{`
+            i += 1 + escaped.chars().next().map_or(0, char::len_utf8);
+        } else {
+            match rest.as_bytes()[0] {
+                b'{' => depth += 1,
+                b'}' => depth = depth.saturating_sub(1),
+                _ => {}
+            }
+            i += rest.chars().next().unwrap().len_utf8();
+        }
+    }
+    Some("equation*")
+}
+
+/// Returns the value of the attribute `name` in the attributes of an html tag.
+fn html_attribute(attrs: &str, name: &str) -> Option<String> {
+    static ATTRIBUTE: LazyLock<Regex> = LazyLock::new(|| {
+        Regex::new(r#"([a-zA-Z_:][a-zA-Z0-9_.:-]*)\s*=\s*(?:"([^"]*)"|'([^']*)'|([^\s"'>]+))"#)
+            .unwrap()
+    });
+    let value = ATTRIBUTE
+        .captures_iter(attrs)
+        .find(|c| c[1].eq_ignore_ascii_case(name))
+        .map(|c| unescape_html(c.get(2).or(c.get(3)).or(c.get(4)).unwrap().as_str()));
+    value
+}
+
+/// Replaces the common html character references in `text`.
+fn unescape_html(text: &str) -> String {
+    text.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&nbsp;", "\u{a0}")
+        .replace("&amp;", "&")
+}
+
+/// Turns a heading ID or table label into a key that is safe to use in
+/// `\label` and `\hyperref`. Characters other than ASCII letters, digits
+/// and `-_.:/` are written as `+` and their UTF-8 bytes in hex, so that
+/// different IDs keep different keys.
+fn label(id: &str) -> String {
+    let mut key = String::with_capacity(id.len());
+    for c in id.chars() {
+        if c.is_ascii_alphanumeric() || "-_.:/".contains(c) {
+            key.push(c);
+        } else {
+            let mut bytes = [0; 4];
+            for byte in c.encode_utf8(&mut bytes).bytes() {
+                key.push_str(&format!("+{:02X}", byte));
+            }
+        }
+    }
+    key
+}
+
+/// Writes `text` with the characters that are special in latex escaped.
+fn escape_latex<W: pulldown_cmark_escape::StrWrite>(
+    mut writer: W,
+    text: &str,
+) -> Result<(), W::Error> {
+    let mut start = 0;
+    for (i, c) in text.char_indices() {
+        let escaped = match c {
+            '\\' => r"\textbackslash{}",
+            '&' => r"\&",
+            '%' => r"\%",
+            '$' => r"\$",
+            '#' => r"\#",
+            '_' => r"\_",
+            '{' => r"\{",
+            '}' => r"\}",
+            '~' => r"\textasciitilde{}",
+            '^' => r"\textasciicircum{}",
+            '\u{a0}' => "~",
+            _ => continue,
+        };
+        writer.write_str(&text[start..i])?;
+        writer.write_str(escaped)?;
+        start = i + c.len_utf8();
+    }
+    writer.write_str(&text[start..])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tex(markdown: &str, opts: &TexOptions) -> String {
+        Markdown(markdown).render_tex_with(opts).0
+    }
+
+    fn fragment() -> TexOptions {
+        TexOptions {
+            fragment: true,
+            ..TexOptions::default()
+        }
+    }
+
+    #[test]
+    fn escapes_unnumbered_headings_in_the_contents() {
+        let opts = TexOptions {
+            toc: true,
+            ..fragment()
+        };
+        let latex = tex("## Sample_1 & 50% $x^2$ `a_b` {.unnumbered}\n", &opts);
+        assert!(latex.contains("\\subsection*{"), "{}", latex);
+        assert!(
+            latex.contains(
+                "\\addcontentsline{toc}{subsection}{Sample\\_1 \\& 50\\% $x^2$ \\texttt{a\\_b}}"
+            ),
+            "{}",
+            latex
+        );
+    }
+
+    #[test]
+    fn translates_inline_html() {
+        let latex = tex(
+            "H<sub>2</sub>O and <kbd>Ctrl</kbd>, <a href='https://x.org/a?b=1'>link</a>\n",
+            &fragment(),
+        );
+        assert!(latex.contains("H\\textsubscript{2}O"), "{}", latex);
+        assert!(latex.contains("\\fbox{\\texttt{Ctrl}}"), "{}", latex);
+        assert!(
+            latex.contains("\\href{https://x.org/a?b=1}{link}"),
+            "{}",
+            latex
+        );
+
+        let latex = tex("<b>bold <i>both</b> done\n", &fragment());
+        assert!(
+            latex.contains("\\textbf{bold \\emph{both}} done"),
+            "{}",
+            latex
+        );
+
+        let latex = tex(
+            "<!-- note -->\n<video src=\"a.mp4\"></video>\n",
+            &fragment(),
+        );
+        assert!(latex.contains("% note"), "{}", latex);
+        assert!(latex.contains("%<video src=\"a.mp4\">"), "{}", latex);
+    }
+
+    #[test]
+    fn breaks_lines_only_inside_a_paragraph() {
+        let latex = tex("<br>First<br>\nsecond\n\n<br/>\n<br>Third\n", &fragment());
+        assert_eq!(latex.matches("\\\\").count(), 1, "{}", latex);
+        assert!(latex.contains("First\\\\\n"), "{}", latex);
+        assert!(!latex.contains("\n\\\\"), "{}", latex);
+    }
+
+    #[test]
+    fn writes_footnotes_from_image_captions() {
+        // pulldown-cmark doesn't nest footnote references in alt text, but
+        // events can be built that do
+        let events = vec![
+            Event::Start(Tag::Paragraph),
+            Event::Start(Tag::Image {
+                link_type: LinkType::Inline,
+                dest_url: "a.png".into(),
+                title: "".into(),
+                id: "".into(),
+            }),
+            Event::Text("Setup".into()),
+            Event::FootnoteReference("photo".into()),
+            Event::End(TagEnd::Image),
+            Event::End(TagEnd::Paragraph),
+            Event::Start(Tag::FootnoteDefinition("photo".into())),
+            Event::Start(Tag::Paragraph),
+            Event::Text("Taken by Ada.".into()),
+            Event::End(TagEnd::Paragraph),
+            Event::End(TagEnd::FootnoteDefinition),
+        ];
+        let mut latex = String::new();
+        LatexWriter::new(events, &mut latex, &fragment())
+            .run()
+            .unwrap();
+        assert!(
+            latex.contains("\\includegraphics{a.png}\n\\footnote[1]{Taken by Ada."),
+            "{}",
+            latex
+        );
+    }
+
+    #[test]
+    fn summarises_the_first_paragraph() {
+        let markdown = Markdown("# Title\n\nMixed *buffer* at `pH 7`\nfor $t_0$.\n\nMore.\n");
+        assert_eq!(
+            markdown.summary(100).as_deref(),
+            Some("Mixed buffer at pH 7 for t_0.")
+        );
+        assert_eq!(markdown.summary(12).as_deref(), Some("Mixed buffer…"));
+        assert_eq!(Markdown("# Only a title\n").summary(100), None);
+    }
+
+    #[test]
+    fn refers_only_to_known_labels() {
+        let latex = tex(
+            "## Yield {#100%}\n\n[up](#100%), [gone](#50%) and [site](https://x.org/#a)\n",
+            &fragment(),
+        );
+        assert!(latex.contains("\\label{sec:100+25}"), "{}", latex);
+        assert!(latex.contains("\\hyperref[sec:100+25]{up}"), "{}", latex);
+        assert!(latex.contains(", gone and"), "{}", latex);
+        assert!(!latex.contains("50%"), "{}", latex);
+        assert!(
+            latex.contains("\\href{https://x.org/#a}{site}"),
+            "{}",
+            latex
+        );
+    }
+
+    #[test]
+    fn links_arxiv_and_doi_references() {
+        let html = Markdown("[arXiv:1706.03762], [hep-th/9711200] and [doi:10.1000/xyz]\n")
+            .render_html()
+            .0
+            .into_string();
+        assert!(
+            html.contains(
+                "<a href=\"https://arxiv.org/abs/1706.03762\" title=\"arXiv:1706.03762\">"
+            ),
+            "{}",
+            html
+        );
+        assert!(
+            html.contains(
+                "<a href=\"https://arxiv.org/abs/hep-th/9711200\" title=\"hep-th/9711200\">"
+            ),
+            "{}",
+            html
+        );
+        assert!(
+            html.contains("<a href=\"https://dx.doi.org/10.1000/xyz\" title=\"doi:10.1000/xyz\">"),
+            "{}",
+            html
+        );
+    }
+
+    #[test]
+    fn wraps_display_math() {
+        assert_eq!(display_math_environment("x = 1"), Some("equation*"));
+        assert_eq!(
+            display_math_environment("a &= b \\\\ c &= d"),
+            Some("align*")
+        );
+        assert_eq!(
+            display_math_environment("\\begin{pmatrix} a \\\\ b \\end{pmatrix}"),
+            Some("equation*")
+        );
+        assert_eq!(
+            display_math_environment("\\begin{align} a \\\\ b \\end{align}"),
+            None
+        );
+    }
+
+    #[test]
+    fn parses_table_captions() {
+        let caption = TableCaption::parse("Table: Results of run 3 {#tbl:run3}").unwrap();
+        assert_eq!(caption.text, "Results of run 3");
+        assert_eq!(caption.label.as_deref(), Some("tbl:run3"));
+
+        let caption = TableCaption::parse("Table: Results {#not a label}").unwrap();
+        assert_eq!(caption.text, "Results {#not a label}");
+        assert_eq!(caption.label, None);
+
+        assert!(TableCaption::parse("Results").is_none());
+    }
+}
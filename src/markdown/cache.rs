@@ -0,0 +1,76 @@
+use std::collections::VecDeque;
+use std::sync::Mutex;
+
+/// Number of rendered blocks each cache keeps
+const CACHE_SIZE: usize = 128;
+
+/// Hash of the parts of a block's source, which names its rendered files.
+/// Uses 64-bit FNV-1a, so the names stay the same between builds.
+pub(super) fn content_hash(parts: &[&[u8]]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for part in parts {
+        // Prefix each part with its length, so that moving bytes from one
+        // part to the next changes the hash
+        for byte in (part.len() as u64).to_le_bytes().iter().chain(*part) {
+            hash ^= u64::from(*byte);
+            hash = hash.wrapping_mul(0x100000001b3);
+        }
+    }
+    hash
+}
+
+/// Rendered blocks kept for the life of the process by the hash of their
+/// source. Only the most recently used are kept.
+pub(super) struct Cache(Mutex<VecDeque<(u64, String)>>);
+
+impl Cache {
+    pub(super) const fn new() -> Cache {
+        Cache(Mutex::new(VecDeque::new()))
+    }
+
+    /// The rendered block with this hash, if it is cached
+    pub(super) fn get(&self, hash: u64) -> Option<String> {
+        let mut entries = self.0.lock().unwrap();
+        let index = entries.iter().position(|(h, _)| *h == hash)?;
+        let entry = entries.remove(index)?;
+        let rendered = entry.1.clone();
+        entries.push_back(entry);
+        Some(rendered)
+    }
+
+    /// Cache a rendered block, dropping the least recently used if the
+    /// cache is full
+    pub(super) fn insert(&self, hash: u64, rendered: String) {
+        let mut entries = self.0.lock().unwrap();
+        entries.retain(|(h, _)| *h != hash);
+        if entries.len() == CACHE_SIZE {
+            entries.pop_front();
+        }
+        entries.push_back((hash, rendered));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hashes_are_stable() {
+        assert_eq!(content_hash(&[]), 0xcbf29ce484222325);
+        assert_eq!(content_hash(&[b"digraph { a -> b }"]), 0xf4b40ffbfb1e1eb8);
+        assert_ne!(content_hash(&[b"ab", b"c"]), content_hash(&[b"a", b"bc"]));
+    }
+
+    #[test]
+    fn keeps_the_most_recently_used() {
+        let cache = Cache::new();
+        for hash in 0..CACHE_SIZE as u64 {
+            cache.insert(hash, hash.to_string());
+        }
+        assert_eq!(cache.get(0).as_deref(), Some("0"));
+        cache.insert(CACHE_SIZE as u64, "new".to_string());
+        assert_eq!(cache.get(0).as_deref(), Some("0"));
+        assert_eq!(cache.get(1), None);
+        assert_eq!(cache.get(CACHE_SIZE as u64).as_deref(), Some("new"));
+    }
+}
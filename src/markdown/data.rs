@@ -0,0 +1,365 @@
+use std::fs::read_to_string;
+use std::ops::{Range, RangeInclusive};
+use std::path::{Component, Path};
+use std::sync::LazyLock;
+
+use pulldown_cmark::{Alignment, CodeBlockKind, CowStr, Event, Tag, TagEnd};
+use regex::Regex;
+
+use super::Diagnostic;
+
+/// Options for including CSV data as a table, given as `key=value` words
+/// after the path of an `![[data.csv]]` directive (separated from it by
+/// `|`) or in the info string of a ```` ```csv ```` block.
+#[derive(Debug, Default)]
+struct CsvOptions {
+    /// File to read the data from, relative to the data directory. A
+    /// ```` ```csv ```` block without one holds the data itself.
+    src: Option<String>,
+
+    /// Columns to include, by header or by number counting from 1
+    /// (`columns=time,3`)
+    columns: Option<Vec<String>>,
+
+    /// Rows to include, counting from 1 after the header: either the first
+    /// few (`rows=10`) or a range (`rows=5-10`)
+    rows: Option<RangeInclusive<usize>>,
+
+    /// Number of decimal places to write numbers with, and whether to use
+    /// scientific notation (`format=.3` or `format=.3e`). Whole numbers are
+    /// only reformatted in scientific notation.
+    format: Option<(usize, bool)>,
+}
+
+impl CsvOptions {
+    fn parse<'w>(words: impl Iterator<Item = &'w str>) -> Result<CsvOptions, String> {
+        let mut opts = CsvOptions::default();
+        for word in words {
+            let (key, value) = word
+                .split_once('=')
+                .ok_or_else(|| format!("expected `key=value`, found `{}`", word))?;
+            match key {
+                "src" => opts.src = Some(value.to_string()),
+                "columns" => opts.columns = Some(value.split(',').map(String::from).collect()),
+                "rows" => {
+                    let parse = |n: &str| {
+                        n.parse::<usize>()
+                            .map_err(|_| format!("invalid row number `{}`", n))
+                    };
+                    opts.rows = Some(match value.split_once('-') {
+                        Some((start, end)) => parse(start)?..=parse(end)?,
+                        None => 1..=parse(value)?,
+                    });
+                }
+                "format" => {
+                    let invalid = || format!("invalid number format `{}`", value);
+                    let precision = value.strip_prefix('.').ok_or_else(invalid)?;
+                    let (precision, scientific) = match precision.strip_suffix('e') {
+                        Some(precision) => (precision, true),
+                        None => (precision.strip_suffix('f').unwrap_or(precision), false),
+                    };
+                    opts.format = Some((precision.parse().map_err(|_| invalid())?, scientific));
+                }
+                _ => return Err(format!("unknown option `{}`", key)),
+            }
+        }
+        Ok(opts)
+    }
+}
+
+/// Builds the events of a table from CSV data, whose first row is the
+/// header.
+fn table_events(data: &str, opts: &CsvOptions) -> Result<Vec<Event<'static>>, String> {
+    let mut reader = csv::ReaderBuilder::new()
+        .flexible(true)
+        .from_reader(data.as_bytes());
+    let header: Vec<String> = reader
+        .headers()
+        .map_err(|e| e.to_string())?
+        .iter()
+        .map(String::from)
+        .collect();
+    let columns: Vec<usize> = match &opts.columns {
+        Some(columns) => columns
+            .iter()
+            .map(|column| column_index(&header, column))
+            .collect::<Result<_, _>>()?,
+        None => (0..header.len()).collect(),
+    };
+
+    let mut rows: Vec<Vec<String>> = Vec::new();
+    for (i, record) in reader.records().enumerate() {
+        let record = record.map_err(|e| e.to_string())?;
+        if opts
+            .rows
+            .as_ref()
+            .is_some_and(|rows| !rows.contains(&(i + 1)))
+        {
+            continue;
+        }
+        let row = columns
+            .iter()
+            .map(|&column| {
+                let cell = record.get(column).unwrap_or_default().trim();
+                // Whole numbers, such as counts and indices, keep their
+                // form unless written in scientific notation
+                let whole = cell.parse::<i64>().is_ok();
+                match (opts.format, cell.parse::<f64>()) {
+                    (Some((precision, true)), Ok(number)) => format!("{:.*e}", precision, number),
+                    (Some((precision, false)), Ok(number)) if !whole => {
+                        format!("{:.*}", precision, number)
+                    }
+                    _ => cell.to_string(),
+                }
+            })
+            .collect();
+        rows.push(row);
+    }
+
+    // Right-align columns of numbers
+    let alignments = (0..columns.len())
+        .map(|i| {
+            let numeric = !rows.is_empty()
+                && rows
+                    .iter()
+                    .all(|row| row[i].is_empty() || row[i].parse::<f64>().is_ok());
+            if numeric {
+                Alignment::Right
+            } else {
+                Alignment::None
+            }
+        })
+        .collect();
+
+    let cells = |row: Vec<String>| {
+        row.into_iter().flat_map(|cell| {
+            [
+                Event::Start(Tag::TableCell),
+                Event::Text(CowStr::from(cell)),
+                Event::End(TagEnd::TableCell),
+            ]
+        })
+    };
+    let mut events = vec![
+        Event::Start(Tag::Table(alignments)),
+        Event::Start(Tag::TableHead),
+    ];
+    events.extend(cells(columns.iter().map(|&i| header[i].clone()).collect()));
+    events.push(Event::End(TagEnd::TableHead));
+    for row in rows {
+        events.push(Event::Start(Tag::TableRow));
+        events.extend(cells(row));
+        events.push(Event::End(TagEnd::TableRow));
+    }
+    events.push(Event::End(TagEnd::Table));
+    Ok(events)
+}
+
+/// Index of a column given by its header or by its number, counting from 1.
+pub(super) fn column_index(header: &[String], column: &str) -> Result<usize, String> {
+    header
+        .iter()
+        .position(|name| name == column)
+        .or_else(|| column.parse::<usize>().ok()?.checked_sub(1))
+        .filter(|&i| i < header.len())
+        .ok_or_else(|| format!("no column `{}`", column))
+}
+
+/// Reads a CSV file relative to the data directory. The path must stay
+/// inside the directory, so that a note can't show other files the server
+/// can read.
+pub(super) fn read_data(dir: &Path, src: &str) -> Result<String, String> {
+    let inside = Path::new(src)
+        .components()
+        .all(|component| matches!(component, Component::Normal(_) | Component::CurDir));
+    if !inside {
+        return Err(format!("{} is outside the notebook", src));
+    }
+    read_to_string(dir.join(src)).map_err(|e| format!("could not read {}: {}", src, e))
+}
+
+/// Replaces CSV data included by an `![[data.csv]]` directive, which must
+/// be a paragraph by itself (and name a file with an extension, unlike a
+/// transcluded note), or by a ```` ```csv ```` block with the table
+/// of the data. Data is read relative to `dir`. Directives that fail are
+/// left as they are, with a diagnostic.
+pub(super) fn include_data<'a>(
+    events: Vec<(Event<'a>, Range<usize>)>,
+    markdown: &str,
+    dir: &Path,
+    diagnostics: &mut Vec<Diagnostic>,
+) -> Vec<(Event<'a>, Range<usize>)> {
+    static DIRECTIVE: LazyLock<Regex> =
+        LazyLock::new(|| Regex::new(r"^!\[\[([^\]|]*\.[^\]|]*?)\s*(?:\|([^\]]*))?\]\]$").unwrap());
+    let mut included = Vec::with_capacity(events.len());
+    let mut events = events.into_iter();
+    while let Some((event, range)) = events.next() {
+        let block_end = match &event {
+            Event::Start(Tag::Paragraph) => TagEnd::Paragraph,
+            Event::Start(Tag::CodeBlock(CodeBlockKind::Fenced(info)))
+                if info.split_whitespace().next() == Some("csv") =>
+            {
+                TagEnd::CodeBlock
+            }
+            _ => {
+                included.push((event, range));
+                continue;
+            }
+        };
+
+        // Collect the block, and the text inside it
+        let mut block = vec![(event, range.clone())];
+        let mut text = String::new();
+        let mut plain = true;
+        for (event, range) in events.by_ref() {
+            match &event {
+                Event::Text(t) => text.push_str(t),
+                Event::End(end) if *end == block_end => {
+                    block.push((event, range));
+                    break;
+                }
+                _ => plain = false,
+            }
+            block.push((event, range));
+        }
+
+        let table = match &block[0].0 {
+            Event::Start(Tag::CodeBlock(CodeBlockKind::Fenced(info))) => {
+                let mut words = info.split_whitespace().skip(1);
+                CsvOptions::parse(&mut words).and_then(|opts| match &opts.src {
+                    Some(src) => table_events(&read_data(dir, src)?, &opts),
+                    None => table_events(&text, &opts),
+                })
+            }
+            _ => match DIRECTIVE.captures(text.trim()).filter(|_| plain) {
+                Some(c) => {
+                    let words = c.get(2).map_or("", |m| m.as_str()).split_whitespace();
+                    CsvOptions::parse(words)
+                        .and_then(|opts| table_events(&read_data(dir, &c[1])?, &opts))
+                }
+                None => {
+                    included.extend(block);
+                    continue;
+                }
+            },
+        };
+        match table {
+            Ok(table) => included.extend(table.into_iter().map(|e| (e, range.clone()))),
+            Err(message) => {
+                let source = markdown[range.clone()].lines().next().unwrap_or_default();
+                diagnostics.push(Diagnostic::new(markdown, range, source, message));
+                included.extend(block);
+            }
+        }
+    }
+    included
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_dir::TestDir;
+
+    #[test]
+    fn parses_options() {
+        let opts =
+            CsvOptions::parse("src=a.csv columns=time,2 rows=5-10 format=.3e".split(' ')).unwrap();
+        assert_eq!(opts.src.as_deref(), Some("a.csv"));
+        assert_eq!(
+            opts.columns,
+            Some(vec!["time".to_string(), "2".to_string()])
+        );
+        assert_eq!(opts.rows, Some(5..=10));
+        assert_eq!(opts.format, Some((3, true)));
+
+        let opts = CsvOptions::parse("rows=4 format=.2f".split(' ')).unwrap();
+        assert_eq!(opts.rows, Some(1..=4));
+        assert_eq!(opts.format, Some((2, false)));
+
+        assert!(CsvOptions::parse(["rows=a-b"].into_iter()).is_err());
+        assert!(CsvOptions::parse(["format=3"].into_iter()).is_err());
+        assert!(CsvOptions::parse(["colour=red"].into_iter()).is_err());
+        assert!(CsvOptions::parse(["columns"].into_iter()).is_err());
+    }
+
+    #[test]
+    fn finds_columns_by_header_or_number() {
+        let header = vec!["time".to_string(), "2".to_string(), "value".to_string()];
+        assert_eq!(column_index(&header, "value"), Ok(2));
+        // Headers take priority over numbers
+        assert_eq!(column_index(&header, "2"), Ok(1));
+        assert_eq!(column_index(&header, "3"), Ok(2));
+        assert!(column_index(&header, "0").is_err());
+        assert!(column_index(&header, "4").is_err());
+        assert!(column_index(&header, "missing").is_err());
+    }
+
+    fn cells(events: &[Event]) -> Vec<String> {
+        events
+            .iter()
+            .filter_map(|event| match event {
+                Event::Text(text) => Some(text.to_string()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn builds_tables() {
+        let data = "time,name,value\n1,a,0.12345\n2,b,1.5\n3,c,2\n";
+        let opts = CsvOptions::parse("columns=value,time rows=2-3 format=.2".split(' ')).unwrap();
+        let events = table_events(data, &opts).unwrap();
+        assert_eq!(cells(&events), ["value", "time", "1.50", "2", "2", "3"]);
+        assert!(matches!(
+            &events[0],
+            Event::Start(Tag::Table(alignments))
+                if alignments == &[Alignment::Right, Alignment::Right]
+        ));
+
+        let opts = CsvOptions::parse("format=.1e".split(' ')).unwrap();
+        let events = table_events(data, &opts).unwrap();
+        assert_eq!(cells(&events)[3..6], ["1.0e0", "a", "1.2e-1"]);
+        assert!(matches!(
+            &events[0],
+            Event::Start(Tag::Table(alignments)) if alignments[1] == Alignment::None
+        ));
+    }
+
+    #[test]
+    fn reads_data_inside_the_directory() {
+        let dir = TestDir::new(&[("data/run.csv", "a,b\n1,2\n")]);
+        assert_eq!(read_data(dir.path(), "data/run.csv").unwrap(), "a,b\n1,2\n");
+        assert_eq!(
+            read_data(dir.path(), "./data/run.csv").unwrap(),
+            "a,b\n1,2\n"
+        );
+    }
+
+    #[test]
+    fn refuses_data_outside_the_directory() {
+        let dir = Path::new("/tmp/notebook");
+        assert!(read_data(dir, "../secret.csv")
+            .unwrap_err()
+            .contains("outside the notebook"));
+        assert!(read_data(dir, "data/../../secret.csv")
+            .unwrap_err()
+            .contains("outside the notebook"));
+        assert!(read_data(dir, "/etc/passwd")
+            .unwrap_err()
+            .contains("outside the notebook"));
+    }
+
+    #[test]
+    fn refused_directives_are_left_with_a_diagnostic() {
+        let markdown = "![[/etc/passwd.csv]]\n";
+        let parsed = super::super::Markdown(markdown);
+        let events = parsed.parser().into_offset_iter().collect();
+        let mut diagnostics = Vec::new();
+        let events = include_data(events, markdown, Path::new("/tmp"), &mut diagnostics);
+        assert!(!events
+            .iter()
+            .any(|(e, _)| matches!(e, Event::Start(Tag::Table(_)))));
+        assert_eq!(diagnostics.len(), 1);
+        assert!(diagnostics[0].message.contains("outside the notebook"));
+    }
+}
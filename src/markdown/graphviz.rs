@@ -0,0 +1,89 @@
+use std::borrow::Cow;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::LazyLock;
+
+use pulldown_cmark::CodeBlockKind;
+use regex::Regex;
+
+use super::cache::{content_hash, Cache};
+
+/// Whether a fenced code block with this info string holds a Graphviz
+/// diagram.
+pub(super) fn is_diagram(kind: &CodeBlockKind) -> bool {
+    matches!(kind, CodeBlockKind::Fenced(info) if info.split_whitespace().next() == Some("dot"))
+}
+
+/// Run the locally installed Graphviz `dot` on a diagram, producing the
+/// given output format, e.g. `svg`.
+fn run_dot(source: &str, format: &str, output: Option<&Path>) -> Result<Vec<u8>, String> {
+    let mut command = Command::new("dot");
+    command.arg(format!("-T{}", format));
+    if let Some(output) = output {
+        command.arg("-o").arg(output);
+    }
+    let mut child = command
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| format!("could not run Graphviz `dot`: {}", e))?;
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(source.as_bytes())
+        .map_err(|e| format!("could not run Graphviz `dot`: {}", e))?;
+    let result = child
+        .wait_with_output()
+        .map_err(|e| format!("could not run Graphviz `dot`: {}", e))?;
+    if result.status.success() {
+        Ok(result.stdout)
+    } else {
+        let stderr = String::from_utf8_lossy(&result.stderr);
+        Err(format!("Graphviz failed: {}", stderr.trim()))
+    }
+}
+
+/// Remove the xml declaration and doctype from an SVG image, which can't
+/// appear when it is inlined in html.
+pub(crate) fn strip_prolog(svg: &str) -> Cow<'_, str> {
+    static PROLOG: LazyLock<Regex> =
+        LazyLock::new(|| Regex::new(r"(?s)<\?xml.*?\?>|<!DOCTYPE.*?>").unwrap());
+    PROLOG.replace_all(svg, "")
+}
+
+/// Render a diagram to inline SVG. Diagrams that render are cached by the
+/// hash of their source.
+pub(super) fn render_svg(source: &str) -> Result<String, String> {
+    static CACHE: Cache = Cache::new();
+    let hash = content_hash(&[source.as_bytes()]);
+    if let Some(svg) = CACHE.get(hash) {
+        return Ok(svg);
+    }
+
+    let svg = String::from_utf8_lossy(&run_dot(source, "svg", None)?).into_owned();
+    let svg = format!(
+        "<div class=\"diagram\">{}</div>\n",
+        strip_prolog(&svg).trim()
+    );
+    CACHE.insert(hash, svg.clone());
+    Ok(svg)
+}
+
+/// Render a diagram to `{dir}/dot-{hash}.pdf`, unless a file rendered from
+/// the same source is already there. Returns the path of the file.
+pub(super) fn render_pdf(source: &str, dir: &Path) -> Result<PathBuf, String> {
+    let path = dir.join(format!(
+        "dot-{:016x}.pdf",
+        content_hash(&[source.as_bytes()])
+    ));
+    if !path.exists() {
+        fs::create_dir_all(dir)
+            .map_err(|e| format!("could not create {}: {}", dir.display(), e))?;
+        run_dot(source, "pdf", Some(&path))?;
+    }
+    Ok(path)
+}
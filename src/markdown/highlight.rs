@@ -0,0 +1,177 @@
+use std::ops::RangeInclusive;
+use std::sync::OnceLock;
+
+use pulldown_cmark::{CodeBlockKind, Event, Tag, TagEnd};
+use pulldown_cmark_escape::escape_html;
+use syntect::easy::HighlightLines;
+use syntect::highlighting::{Color, ThemeSet};
+use syntect::html::{styled_line_to_highlighted_html, IncludeBackground};
+use syntect::parsing::SyntaxSet;
+use syntect::util::LinesWithEndings;
+
+/// Options for a code block, given in the info string of its fence after
+/// the language, e.g. ```` ```rust linenos hl_lines=2-4,7 ````.
+#[derive(Debug, Default)]
+pub(super) struct CodeInfo<'i> {
+    /// Language of the code, if given
+    pub lang: Option<&'i str>,
+
+    /// Number the lines
+    pub linenos: bool,
+
+    /// Ranges of lines to highlight, counting from 1
+    pub hl_lines: Vec<RangeInclusive<usize>>,
+}
+
+impl<'i> CodeInfo<'i> {
+    /// Parse the info string of a fenced code block. Options that aren't
+    /// recognised are ignored.
+    pub fn parse(info: &'i str) -> CodeInfo<'i> {
+        let mut words = info.split_whitespace();
+        let mut code = CodeInfo {
+            lang: words.next(),
+            ..CodeInfo::default()
+        };
+        for word in words {
+            if word == "linenos" {
+                code.linenos = true;
+            } else if let Some(lines) = word.strip_prefix("hl_lines=") {
+                code.hl_lines = lines
+                    .trim_matches(|c| c == '"' || c == '{' || c == '}')
+                    .split(',')
+                    .filter_map(|range| match range.split_once('-') {
+                        Some((start, end)) => Some(start.parse().ok()?..=end.parse().ok()?),
+                        None => range.parse().ok().map(|line| line..=line),
+                    })
+                    .collect();
+            }
+        }
+        code
+    }
+
+    /// Whether a line (counting from 1) is highlighted
+    fn highlighted(&self, line: usize) -> bool {
+        self.hl_lines.iter().any(|range| range.contains(&line))
+    }
+}
+
+fn syntax_set() -> &'static SyntaxSet {
+    static SYNTAXES: OnceLock<SyntaxSet> = OnceLock::new();
+    SYNTAXES.get_or_init(SyntaxSet::load_defaults_newlines)
+}
+
+fn theme_set() -> &'static ThemeSet {
+    static THEMES: OnceLock<ThemeSet> = OnceLock::new();
+    THEMES.get_or_init(ThemeSet::load_defaults)
+}
+
+fn css_color(color: Color) -> String {
+    format!("#{:02x}{:02x}{:02x}", color.r, color.g, color.b)
+}
+
+/// Highlight code as html, coloured with the solarized theme matching the
+/// page (light or dark).
+pub(super) fn highlight_html(code: &str, info: &CodeInfo, light: bool) -> String {
+    let syntaxes = syntax_set();
+    let syntax = info
+        .lang
+        .and_then(|lang| syntaxes.find_syntax_by_token(lang))
+        .unwrap_or_else(|| syntaxes.find_syntax_plain_text());
+    let theme = &theme_set().themes[if light {
+        "Solarized (light)"
+    } else {
+        "Solarized (dark)"
+    }];
+    let background = theme.settings.background.map(css_color);
+    // Solarized base2 and base02, one step away from the background
+    let line_highlight = if light { "#eee8d5" } else { "#073642" };
+
+    let mut html = String::from("<pre class=\"highlight\"");
+    if let Some(background) = &background {
+        html.push_str(&format!(" style=\"background-color:{};\"", background));
+    }
+    html.push('>');
+    if let Some(lang) = info.lang {
+        html.push_str("<code class=\"language-");
+        escape_html(&mut html, lang).unwrap();
+        html.push_str("\">");
+    } else {
+        html.push_str("<code>");
+    }
+
+    let lines = LinesWithEndings::from(code).count();
+    let width = lines.to_string().len();
+    let mut highlighter = HighlightLines::new(syntax, theme);
+    for (i, line) in LinesWithEndings::from(code).enumerate() {
+        let number = i + 1;
+        let highlighted = info.highlighted(number);
+        // A highlighted line is a block, which ends the line by itself
+        let line = if highlighted {
+            html.push_str(&format!(
+                "<span class=\"hll\" style=\"display:block;background-color:{};\">",
+                line_highlight
+            ));
+            line.strip_suffix('\n').unwrap_or(line)
+        } else {
+            line
+        };
+        if info.linenos {
+            html.push_str(&format!(
+                "<span class=\"lineno\" style=\"user-select:none;opacity:0.5;\">{:>width$} </span>",
+                number,
+                width = width
+            ));
+        }
+        match highlighter
+            .highlight_line(line, syntaxes)
+            .and_then(|ranges| styled_line_to_highlighted_html(&ranges, IncludeBackground::No))
+        {
+            Ok(line) => html.push_str(&line),
+            Err(_) => escape_html(&mut html, line).unwrap(),
+        }
+        if highlighted {
+            html.push_str("</span>");
+        }
+    }
+    html.push_str("</code></pre>\n");
+    html
+}
+
+/// Replaces each code block with html highlighted by syntect.
+pub(super) struct HighlightMiddleware<I> {
+    iter: I,
+    light: bool,
+}
+
+impl<I> HighlightMiddleware<I> {
+    pub fn new(iter: I, light: bool) -> Self {
+        HighlightMiddleware { iter, light }
+    }
+}
+
+impl<'a, I> Iterator for HighlightMiddleware<I>
+where
+    I: Iterator<Item = Event<'a>>,
+{
+    type Item = Event<'a>;
+
+    fn next(&mut self) -> Option<Event<'a>> {
+        let event = self.iter.next()?;
+        let Event::Start(Tag::CodeBlock(kind)) = event else {
+            return Some(event);
+        };
+        let mut code = String::new();
+        for event in self.iter.by_ref() {
+            match event {
+                Event::Text(text) => code.push_str(&text),
+                Event::End(TagEnd::CodeBlock) => break,
+                _ => {}
+            }
+        }
+        let info = match &kind {
+            CodeBlockKind::Fenced(info) => CodeInfo::parse(info),
+            CodeBlockKind::Indented => CodeInfo::default(),
+        };
+        Some(Event::Html(highlight_html(&code, &info, self.light).into()))
+    }
+}
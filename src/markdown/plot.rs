@@ -0,0 +1,561 @@
+use std::fmt::Write;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use pulldown_cmark::CodeBlockKind;
+use pulldown_cmark_escape::escape_html;
+use serde::Deserialize;
+
+use super::cache::{content_hash, Cache};
+use super::data::{column_index, read_data};
+use super::escape_latex;
+
+/// Whether a fenced code block with this info string holds a plot.
+pub(super) fn is_plot(kind: &CodeBlockKind) -> bool {
+    matches!(kind, CodeBlockKind::Fenced(info) if info.split_whitespace().next() == Some("plot"))
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum PlotType {
+    #[default]
+    Line,
+    Scatter,
+    Bar,
+}
+
+/// A column, by header or by number counting from 1
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum Column {
+    Header(String),
+    Number(u64),
+}
+
+impl Column {
+    fn index(&self, header: &[String]) -> Result<usize, String> {
+        match self {
+            Column::Header(column) => column_index(header, column),
+            Column::Number(number) => column_index(header, &number.to_string()),
+        }
+    }
+}
+
+/// One column, or a list of them
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum Columns {
+    One(Column),
+    Many(Vec<Column>),
+}
+
+/// The yaml in a ```` ```plot ```` block, e.g.
+///
+/// ```yaml
+/// data: data/run-03.csv
+/// x: time
+/// y: [temperature, setpoint]
+/// type: line
+/// xlabel: Time (s)
+/// ylabel: Temperature (°C)
+/// ```
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PlotSpec {
+    /// CSV file holding the data, relative to the data directory
+    data: String,
+
+    /// Column of x values
+    x: Column,
+
+    /// Column or columns of y values, each plotted as a series
+    y: Columns,
+
+    /// Whether to draw lines, points or bars (with x values as categories)
+    #[serde(default, rename = "type")]
+    kind: PlotType,
+
+    title: Option<String>,
+    xlabel: Option<String>,
+    ylabel: Option<String>,
+}
+
+/// A plot with its data read from the CSV file.
+struct Plot {
+    spec: PlotSpec,
+
+    /// x value of each row, as written in the file
+    x: Vec<String>,
+
+    /// Name and y values of each series, which are missing for empty cells
+    series: Vec<(String, Vec<Option<f64>>)>,
+}
+
+impl Plot {
+    /// Parse the spec of a plot and read its data, which is relative to
+    /// `dir`. Also returns a hash of the spec and data, which names the
+    /// rendered plot.
+    fn load(source: &str, dir: &Path) -> Result<(Plot, u64), String> {
+        let spec: PlotSpec =
+            serde_yaml::from_str(source).map_err(|e| format!("invalid plot: {}", e))?;
+        let data = read_data(dir, &spec.data)?;
+        let hash = content_hash(&[source.as_bytes(), data.as_bytes()]);
+
+        let mut reader = csv::ReaderBuilder::new()
+            .flexible(true)
+            .from_reader(data.as_bytes());
+        let header: Vec<String> = reader
+            .headers()
+            .map_err(|e| e.to_string())?
+            .iter()
+            .map(String::from)
+            .collect();
+        let x = spec.x.index(&header)?;
+        let y: Vec<usize> = match &spec.y {
+            Columns::One(column) => vec![column.index(&header)?],
+            Columns::Many(columns) => columns
+                .iter()
+                .map(|column| column.index(&header))
+                .collect::<Result<_, _>>()?,
+        };
+
+        let mut plot = Plot {
+            x: Vec::new(),
+            series: y.iter().map(|&i| (header[i].clone(), Vec::new())).collect(),
+            spec,
+        };
+        for record in reader.records() {
+            let record = record.map_err(|e| e.to_string())?;
+            // Skip rows without an x value
+            let x = record.get(x).unwrap_or_default().trim();
+            if x.is_empty() {
+                continue;
+            }
+            plot.x.push(x.to_string());
+            for (&column, (name, values)) in y.iter().zip(&mut plot.series) {
+                let cell = record.get(column).unwrap_or_default().trim();
+                values.push(if cell.is_empty() {
+                    None
+                } else {
+                    Some(
+                        cell.parse().map_err(|_| {
+                            format!("`{}` in column `{}` is not a number", cell, name)
+                        })?,
+                    )
+                });
+            }
+        }
+        if plot.spec.kind != PlotType::Bar {
+            if let Some(cell) = plot.x.iter().find(|x| x.parse::<f64>().is_err()) {
+                return Err(format!(
+                    "`{}` in column `{}` is not a number (only bar plots have categories)",
+                    cell, header[x]
+                ));
+            }
+        }
+        Ok((plot, hash))
+    }
+
+    /// Points of a series with both values, for line and scatter plots
+    fn points<'p>(&'p self, values: &'p [Option<f64>]) -> impl Iterator<Item = (f64, f64)> + 'p {
+        self.x
+            .iter()
+            .zip(values)
+            .filter_map(|(x, y)| Some((x.parse().ok()?, (*y)?)))
+    }
+
+    fn y_values(&self) -> impl Iterator<Item = f64> + '_ {
+        self.series
+            .iter()
+            .flat_map(|(_, values)| values.iter().flatten().copied())
+    }
+}
+
+/// Solarized accent colours, for each series in turn
+const COLOURS: [&str; 8] = [
+    "#268bd2", "#dc322f", "#859900", "#b58900", "#6c71c4", "#2aa198", "#cb4b16", "#d33682",
+];
+
+const WIDTH: f64 = 640.0;
+const HEIGHT: f64 = 400.0;
+
+/// An axis covering a range of values, with evenly spaced ticks at round
+/// numbers.
+struct Axis {
+    min: f64,
+    max: f64,
+    step: f64,
+}
+
+impl Axis {
+    fn new(values: impl Iterator<Item = f64>, include_zero: bool) -> Axis {
+        let (mut min, mut max) = values
+            .fold((f64::INFINITY, f64::NEG_INFINITY), |(min, max), v| {
+                (min.min(v), max.max(v))
+            });
+        if include_zero {
+            min = min.min(0.0);
+            max = max.max(0.0);
+        }
+        if !min.is_finite() {
+            (min, max) = (0.0, 1.0);
+        } else if min == max {
+            (min, max) = (min - 1.0, max + 1.0);
+        }
+        // Aim for about five ticks, a step of 1, 2 or 5 times a power of 10
+        let rough = (max - min) / 5.0;
+        let magnitude = 10f64.powf(rough.log10().floor());
+        let step = [1.0, 2.0, 5.0, 10.0]
+            .into_iter()
+            .map(|m| m * magnitude)
+            .find(|&step| step >= rough)
+            .unwrap();
+        Axis {
+            min: (min / step).floor() * step,
+            max: (max / step).ceil() * step,
+            step,
+        }
+    }
+
+    fn ticks(&self) -> impl Iterator<Item = f64> + '_ {
+        let count = ((self.max - self.min) / self.step).round() as usize;
+        (0..=count).map(move |i| self.min + i as f64 * self.step)
+    }
+
+    /// Label of a tick, with just enough decimal places for the step
+    fn label(&self, value: f64) -> String {
+        let decimals = (-self.step.log10().floor()).max(0.0) as usize;
+        // Avoid labelling zero as `-0` from rounding errors
+        let value = if value.abs() < self.step / 1e6 {
+            0.0
+        } else {
+            value
+        };
+        format!("{:.*}", decimals, value)
+    }
+
+    /// Position of a value between `start` and `end`
+    fn scale(&self, value: f64, start: f64, end: f64) -> f64 {
+        start + (value - self.min) / (self.max - self.min) * (end - start)
+    }
+}
+
+fn escape(text: &str) -> String {
+    let mut escaped = String::new();
+    escape_html(&mut escaped, text).unwrap();
+    escaped
+}
+
+/// Draw a plot as an SVG, with text in the colour of the page.
+fn draw_svg(plot: &Plot) -> String {
+    let spec = &plot.spec;
+    let left = 70.0;
+    let right = WIDTH - 20.0;
+    let top = if spec.title.is_some() { 40.0 } else { 20.0 };
+    let bottom = HEIGHT - 50.0;
+
+    let mut svg = String::new();
+    write!(
+        svg,
+        "<svg xmlns=\"http://www.w3.org/2000/svg\" viewBox=\"0 0 {w} {h}\" width=\"{w}\" height=\"{h}\" \
+         font-family=\"sans-serif\" font-size=\"12\" fill=\"currentColor\">",
+        w = WIDTH,
+        h = HEIGHT
+    )
+    .unwrap();
+    if let Some(title) = &spec.title {
+        write!(
+            svg,
+            "<text x=\"{}\" y=\"20\" text-anchor=\"middle\" font-size=\"14\">{}</text>",
+            (left + right) / 2.0,
+            escape(title)
+        )
+        .unwrap();
+    }
+
+    // y axis, with grid lines
+    let y_axis = Axis::new(plot.y_values(), spec.kind == PlotType::Bar);
+    for tick in y_axis.ticks() {
+        let y = y_axis.scale(tick, bottom, top);
+        write!(
+            svg,
+            "<line x1=\"{left}\" y1=\"{y:.1}\" x2=\"{right}\" y2=\"{y:.1}\" stroke=\"currentColor\" stroke-opacity=\"0.15\"/>\
+             <text x=\"{}\" y=\"{:.1}\" text-anchor=\"end\">{}</text>",
+            left - 6.0,
+            y + 4.0,
+            y_axis.label(tick),
+        )
+        .unwrap();
+    }
+
+    // x axis, and the data
+    match spec.kind {
+        PlotType::Bar => {
+            let band = (right - left) / plot.x.len().max(1) as f64;
+            let width = band * 0.8 / plot.series.len() as f64;
+            let zero = y_axis.scale(0.0, bottom, top);
+            for (i, x) in plot.x.iter().enumerate() {
+                write!(
+                    svg,
+                    "<text x=\"{:.1}\" y=\"{}\" text-anchor=\"middle\">{}</text>",
+                    left + band * (i as f64 + 0.5),
+                    bottom + 16.0,
+                    escape(x)
+                )
+                .unwrap();
+            }
+            for (s, ((_, values), colour)) in
+                plot.series.iter().zip(COLOURS.iter().cycle()).enumerate()
+            {
+                for (i, value) in values.iter().enumerate() {
+                    let Some(value) = value else { continue };
+                    let y = y_axis.scale(*value, bottom, top);
+                    write!(
+                        svg,
+                        "<rect x=\"{:.1}\" y=\"{:.1}\" width=\"{:.1}\" height=\"{:.1}\" fill=\"{}\"/>",
+                        left + band * (i as f64 + 0.1) + width * s as f64,
+                        y.min(zero),
+                        width,
+                        (y - zero).abs(),
+                        colour
+                    )
+                    .unwrap();
+                }
+            }
+        }
+        PlotType::Line | PlotType::Scatter => {
+            let x_axis = Axis::new(plot.x.iter().filter_map(|x| x.parse().ok()), false);
+            for tick in x_axis.ticks() {
+                write!(
+                    svg,
+                    "<text x=\"{:.1}\" y=\"{}\" text-anchor=\"middle\">{}</text>",
+                    x_axis.scale(tick, left, right),
+                    bottom + 16.0,
+                    x_axis.label(tick)
+                )
+                .unwrap();
+            }
+            for ((_, values), colour) in plot.series.iter().zip(COLOURS.iter().cycle()) {
+                let points = plot
+                    .points(values)
+                    .map(|(x, y)| (x_axis.scale(x, left, right), y_axis.scale(y, bottom, top)));
+                if spec.kind == PlotType::Line {
+                    let points: Vec<String> =
+                        points.map(|(x, y)| format!("{:.1},{:.1}", x, y)).collect();
+                    write!(
+                        svg,
+                        "<polyline points=\"{}\" fill=\"none\" stroke=\"{}\" stroke-width=\"2\"/>",
+                        points.join(" "),
+                        colour
+                    )
+                    .unwrap();
+                } else {
+                    for (x, y) in points {
+                        write!(
+                            svg,
+                            "<circle cx=\"{:.1}\" cy=\"{:.1}\" r=\"3\" fill=\"{}\"/>",
+                            x, y, colour
+                        )
+                        .unwrap();
+                    }
+                }
+            }
+        }
+    }
+    write!(
+        svg,
+        "<path d=\"M{left} {top}V{bottom}H{right}\" fill=\"none\" stroke=\"currentColor\"/>"
+    )
+    .unwrap();
+
+    if let Some(xlabel) = &spec.xlabel {
+        write!(
+            svg,
+            "<text x=\"{}\" y=\"{}\" text-anchor=\"middle\">{}</text>",
+            (left + right) / 2.0,
+            HEIGHT - 10.0,
+            escape(xlabel)
+        )
+        .unwrap();
+    }
+    if let Some(ylabel) = &spec.ylabel {
+        let y = (top + bottom) / 2.0;
+        write!(
+            svg,
+            "<text x=\"16\" y=\"{y}\" text-anchor=\"middle\" transform=\"rotate(-90 16 {y})\">{}</text>",
+            escape(ylabel)
+        )
+        .unwrap();
+    }
+    // Legend, if there is more than one series
+    if plot.series.len() > 1 {
+        for (i, ((name, _), colour)) in plot.series.iter().zip(COLOURS.iter().cycle()).enumerate() {
+            let y = top + 10.0 + 18.0 * i as f64;
+            write!(
+                svg,
+                "<rect x=\"{}\" y=\"{}\" width=\"12\" height=\"12\" fill=\"{}\"/>\
+                 <text x=\"{}\" y=\"{}\" text-anchor=\"end\">{}</text>",
+                right - 12.0,
+                y - 10.0,
+                colour,
+                right - 18.0,
+                y,
+                escape(name)
+            )
+            .unwrap();
+        }
+    }
+    svg.push_str("</svg>");
+    svg
+}
+
+/// Render a plot to inline SVG. Plots that render are cached by the hash of
+/// their spec and data.
+pub(super) fn render_svg(source: &str, dir: &Path) -> Result<String, String> {
+    static CACHE: Cache = Cache::new();
+    let (plot, hash) = Plot::load(source, dir)?;
+    if let Some(svg) = CACHE.get(hash) {
+        return Ok(svg);
+    }
+
+    let svg = format!("<div class=\"plot\">{}</div>\n", draw_svg(&plot));
+    CACHE.insert(hash, svg.clone());
+    Ok(svg)
+}
+
+fn latex(text: &str) -> String {
+    let mut escaped = String::new();
+    escape_latex(&mut escaped, text).unwrap();
+    escaped
+}
+
+/// Draw a plot with pgfplots.
+fn draw_pgf(plot: &Plot) -> String {
+    let spec = &plot.spec;
+    let mut options = Vec::new();
+    for (key, label) in [
+        ("title", &spec.title),
+        ("xlabel", &spec.xlabel),
+        ("ylabel", &spec.ylabel),
+    ] {
+        if let Some(label) = label {
+            options.push(format!("{}={{{}}}", key, latex(label)));
+        }
+    }
+    if spec.kind == PlotType::Bar {
+        // Braces keep commas in a category from splitting it
+        let categories: Vec<String> = plot.x.iter().map(|x| format!("{{{}}}", latex(x))).collect();
+        options.push("ybar".to_string());
+        options.push(format!("symbolic x coords={{{}}}", categories.join(",")));
+        options.push("xtick=data".to_string());
+    }
+    if plot.series.len() > 1 {
+        options.push("legend pos=outer north east".to_string());
+    }
+
+    let mut pgf = String::from("\\begin{tikzpicture}\n\\begin{axis}[");
+    pgf.push_str(&options.join(", "));
+    pgf.push_str("]\n");
+    for (name, values) in &plot.series {
+        pgf.push_str(match spec.kind {
+            PlotType::Line => "\\addplot+[no markers] coordinates {",
+            PlotType::Scatter => "\\addplot+[only marks] coordinates {",
+            PlotType::Bar => "\\addplot coordinates {",
+        });
+        for (x, y) in plot.x.iter().zip(values) {
+            if let Some(y) = y {
+                let x = match spec.kind {
+                    PlotType::Bar => format!("{{{}}}", latex(x)),
+                    _ => x.clone(),
+                };
+                write!(pgf, "({},{}) ", x, y).unwrap();
+            }
+        }
+        pgf.push_str("};\n");
+        if plot.series.len() > 1 {
+            writeln!(pgf, "\\addlegendentry{{{}}}", latex(name)).unwrap();
+        }
+    }
+    pgf.push_str("\\end{axis}\n\\end{tikzpicture}\n");
+    pgf
+}
+
+/// Render a plot with pgfplots to `{dir}/plot-{hash}.tex`, unless a file
+/// rendered from the same spec and data is already there. Data is read
+/// relative to `data_dir`. Returns the path of the file.
+pub(super) fn render_pgf(source: &str, data_dir: &Path, dir: &Path) -> Result<PathBuf, String> {
+    let (plot, hash) = Plot::load(source, data_dir)?;
+    let path = dir.join(format!("plot-{:016x}.tex", hash));
+    if !path.exists() {
+        fs::create_dir_all(dir)
+            .map_err(|e| format!("could not create {}: {}", dir.display(), e))?;
+        fs::write(&path, draw_pgf(&plot))
+            .map_err(|e| format!("could not write {}: {}", path.display(), e))?;
+    }
+    Ok(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_dir::TestDir;
+
+    #[test]
+    fn loads_series_by_header_and_number() {
+        let dir = TestDir::new(&[("data.csv", "t,a,b\n0,1,2\n,5,5\n1,3,\n")]);
+        let (plot, _) = Plot::load("data: data.csv\nx: t\ny: [a, 3]\n", dir.path()).unwrap();
+        assert_eq!(plot.x, ["0", "1"]);
+        assert_eq!(
+            plot.series[0],
+            ("a".to_string(), vec![Some(1.0), Some(3.0)])
+        );
+        assert_eq!(plot.series[1], ("b".to_string(), vec![Some(2.0), None]));
+    }
+
+    #[test]
+    fn only_bar_plots_have_categories() {
+        let dir = TestDir::new(&[("data.csv", "sample,count\nA,1\n\"B, C\",2\n")]);
+        let error = Plot::load("data: data.csv\nx: sample\ny: count\n", dir.path())
+            .err()
+            .unwrap();
+        assert!(error.contains("only bar plots have categories"));
+
+        let source = "data: data.csv\nx: sample\ny: count\ntype: bar\n";
+        let (plot, _) = Plot::load(source, dir.path()).unwrap();
+        let pgf = draw_pgf(&plot);
+        assert!(pgf.contains("symbolic x coords={{A},{B, C}}"));
+        assert!(pgf.contains("({B, C},2)"));
+    }
+
+    #[test]
+    fn refuses_data_outside_the_notebook() {
+        let dir = TestDir::new(&[("data.csv", "a,b\n1,2\n")]);
+        for data in ["../data.csv", "/etc/passwd"] {
+            let source = format!("data: {}\nx: 1\ny: 2\n", data);
+            let error = Plot::load(&source, &dir.path().join("notes"))
+                .err()
+                .unwrap();
+            assert!(error.contains("outside the notebook"), "{}", error);
+        }
+    }
+
+    #[test]
+    fn axes_have_round_ticks() {
+        let axis = Axis::new([0.3, 9.2].into_iter(), false);
+        assert_eq!((axis.min, axis.max, axis.step), (0.0, 10.0, 2.0));
+        assert_eq!(axis.ticks().count(), 6);
+
+        let axis = Axis::new([-0.013, 0.021].into_iter(), false);
+        assert_eq!(axis.step, 0.01);
+        assert_eq!(axis.label(axis.min), "-0.02");
+        assert_eq!(axis.label(axis.min + 2.0 * axis.step), "0.00");
+
+        // Bars start from zero, and a single value gets some room
+        let axis = Axis::new([5.0].into_iter(), true);
+        assert_eq!(axis.min, 0.0);
+        let axis = Axis::new([5.0].into_iter(), false);
+        assert!(axis.min < 5.0 && axis.max > 5.0);
+        let axis = Axis::new(std::iter::empty(), false);
+        assert_eq!((axis.min, axis.max), (0.0, 1.0));
+    }
+}
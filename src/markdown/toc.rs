@@ -0,0 +1,225 @@
+use std::collections::HashSet;
+
+use pulldown_cmark::{CowStr, Event, Tag, TagEnd};
+use pulldown_cmark_escape::{escape_href, escape_html};
+use serde::Serialize;
+
+/// A heading listed in a table of contents.
+#[derive(Clone, Debug, Serialize)]
+pub(crate) struct TocEntry {
+    /// Level of the heading, from 1 to 6
+    pub level: usize,
+
+    /// ID of the heading, which links to it
+    pub id: String,
+
+    /// Text of the heading, without formatting
+    pub text: String,
+}
+
+/// Turn the text of a heading into an ID: lower case, with spaces replaced
+/// by `-` and punctuation dropped.
+pub(super) fn slugify(text: &str) -> String {
+    let mut slug = String::new();
+    for c in text.trim().chars() {
+        if c.is_alphanumeric() || c == '-' || c == '_' {
+            slug.extend(c.to_lowercase());
+        } else if c.is_whitespace() && !slug.ends_with('-') {
+            slug.push('-');
+        }
+    }
+    if slug.is_empty() {
+        slug.push_str("section");
+    }
+    slug
+}
+
+/// Text of the heading starting at item `i`, without formatting.
+fn heading_text<'a, T>(items: &[T], i: usize, event: fn(&T) -> &Event<'a>) -> String {
+    let mut text = String::new();
+    for item in &items[i + 1..] {
+        match event(item) {
+            Event::Text(t) | Event::Code(t) | Event::InlineMath(t) => text.push_str(t),
+            Event::End(TagEnd::Heading(_)) => break,
+            _ => {}
+        }
+    }
+    text
+}
+
+/// Give every heading an ID, keeping those given with `{#id}` and slugging
+/// the text of the others, with a number added to tell apart headings with
+/// the same text. Returns the headings, in order.
+pub(super) fn heading_ids<'a, T>(
+    items: &mut [T],
+    event: fn(&T) -> &Event<'a>,
+    event_mut: fn(&mut T) -> &mut Event<'a>,
+) -> Vec<TocEntry> {
+    // Custom IDs take priority over generated ones
+    let mut used: HashSet<String> = items
+        .iter()
+        .filter_map(|item| match event(item) {
+            Event::Start(Tag::Heading { id: Some(id), .. }) => Some(id.to_string()),
+            _ => None,
+        })
+        .collect();
+
+    let mut entries = Vec::new();
+    for i in 0..items.len() {
+        let Event::Start(Tag::Heading { level, id, .. }) = event(&items[i]) else {
+            continue;
+        };
+        let level = *level as usize;
+        let text = heading_text(items, i, event);
+        let id = match id {
+            Some(id) => id.to_string(),
+            None => {
+                let slug = slugify(&text);
+                let mut id = slug.clone();
+                let mut n = 1;
+                while used.contains(&id) {
+                    id = format!("{}-{}", slug, n);
+                    n += 1;
+                }
+                used.insert(id.clone());
+                if let Event::Start(Tag::Heading { id: heading_id, .. }) = event_mut(&mut items[i])
+                {
+                    *heading_id = Some(CowStr::from(id.clone()));
+                }
+                id
+            }
+        };
+        entries.push(TocEntry {
+            level,
+            id,
+            text: text.trim().to_string(),
+        });
+    }
+    entries
+}
+
+/// If the items following the start of a paragraph make it a `[TOC]`
+/// marker, the number of them up to and including the end of the paragraph.
+pub(super) fn marker_len<'a, T>(rest: &[T], event: fn(&T) -> &Event<'a>) -> Option<usize> {
+    let mut text = String::new();
+    for (i, item) in rest.iter().enumerate() {
+        match event(item) {
+            Event::Text(t) => text.push_str(t),
+            Event::End(TagEnd::Paragraph) if text.trim() == "[TOC]" => return Some(i + 1),
+            _ => return None,
+        }
+    }
+    None
+}
+
+/// Whether there is a `[TOC]` marker anywhere in the document.
+pub(super) fn has_marker<'a, T>(items: &[T], event: fn(&T) -> &Event<'a>) -> bool {
+    (0..items.len()).any(|i| {
+        matches!(event(&items[i]), Event::Start(Tag::Paragraph))
+            && marker_len(&items[i + 1..], event).is_some()
+    })
+}
+
+/// Render a table of contents as nested lists of links to the headings.
+pub(super) fn toc_html(entries: &[TocEntry]) -> String {
+    let mut html = String::from("<nav class=\"toc\">");
+    let mut open: Vec<usize> = Vec::new();
+    for entry in entries {
+        while open.last().is_some_and(|&level| level > entry.level) {
+            open.pop();
+            html.push_str("</li></ul>");
+        }
+        match open.last() {
+            Some(&level) if level == entry.level => html.push_str("</li>"),
+            _ => {
+                html.push_str("<ul>");
+                open.push(entry.level);
+            }
+        }
+        html.push_str("<li><a href=\"#");
+        escape_href(&mut html, &entry.id).unwrap();
+        html.push_str("\">");
+        escape_html(&mut html, &entry.text).unwrap();
+        html.push_str("</a>");
+    }
+    for _ in open {
+        html.push_str("</li></ul>");
+    }
+    html.push_str("</nav>\n");
+    html
+}
+
+/// Adds a permalink to the end of each heading, and replaces each `[TOC]`
+/// marker with the table of contents. Returns whether there was a marker.
+pub(super) fn link_headings<'a, R: Clone>(
+    events: Vec<(Event<'a>, R)>,
+    entries: &[TocEntry],
+) -> (Vec<(Event<'a>, R)>, bool) {
+    let mut linked = Vec::with_capacity(events.len());
+    let mut marker = false;
+    let mut heading_id = None;
+    let mut i = 0;
+    while i < events.len() {
+        let (event, range) = &events[i];
+        match event {
+            Event::Start(Tag::Heading { id, .. }) => heading_id = id.clone(),
+            Event::End(TagEnd::Heading(_)) => {
+                if let Some(id) = heading_id.take() {
+                    let mut link = String::from(" <a class=\"permalink\" href=\"#");
+                    escape_href(&mut link, &id).unwrap();
+                    link.push_str("\" aria-label=\"Permalink\">¶</a>");
+                    linked.push((Event::InlineHtml(CowStr::from(link)), range.clone()));
+                }
+            }
+            Event::Start(Tag::Paragraph) => {
+                if let Some(len) = marker_len(&events[i + 1..], |(e, _)| e) {
+                    marker = true;
+                    let toc = CowStr::from(toc_html(entries));
+                    linked.push((Event::Html(toc), range.clone()));
+                    i += len + 1;
+                    continue;
+                }
+            }
+            _ => {}
+        }
+        linked.push(events[i].clone());
+        i += 1;
+    }
+    (linked, marker)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pulldown_cmark::{Options, Parser};
+
+    #[test]
+    fn slugifies_heading_text() {
+        assert_eq!(slugify("Results & Discussion"), "results-discussion");
+        assert_eq!(slugify("  Run 2: pH_7 (final)  "), "run-2-ph_7-final");
+        assert_eq!(slugify("Größe"), "größe");
+        assert_eq!(slugify("???"), "section");
+    }
+
+    #[test]
+    fn gives_headings_unique_ids() {
+        let markdown = "# Method\n\n## Run {#method}\n\n## Method\n\n## `Method`\n";
+        let mut events: Vec<Event> =
+            Parser::new_ext(markdown, Options::ENABLE_HEADING_ATTRIBUTES).collect();
+        let entries = heading_ids(&mut events, |e| e, |e| e);
+        let ids: Vec<&str> = entries.iter().map(|entry| entry.id.as_str()).collect();
+        // The custom ID keeps its name, and the slugs make way for it
+        assert_eq!(ids, ["method-1", "method", "method-2", "method-3"]);
+        assert_eq!(entries[3].text, "Method");
+        assert_eq!(entries[1].level, 2);
+
+        let written: Vec<String> = events
+            .iter()
+            .filter_map(|event| match event {
+                Event::Start(Tag::Heading { id: Some(id), .. }) => Some(id.to_string()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(written, ids);
+    }
+}
@@ -0,0 +1,371 @@
+use std::fs::read_to_string;
+use std::ops::Range;
+use std::path::Path;
+use std::sync::LazyLock;
+
+use pulldown_cmark::{CodeBlockKind, CowStr, Event, Tag, TagEnd};
+use regex::Regex;
+
+use super::toc::slugify;
+use super::{Diagnostic, Markdown};
+
+/// How deeply notes can be transcluded into each other
+const MAX_DEPTH: usize = 8;
+
+/// Replaces each `![[note-id]]` or `![[note-id#heading]]` directive, which
+/// must be a paragraph by itself, with the content of that note (or of the
+/// section under that heading) from `{dir}/{id}.md`. Notes transcluded into
+/// it are expanded in turn, up to a depth limit. Directives that fail are
+/// left as they are, with a diagnostic. `id` is the ID of the note being
+/// rendered, if it has one, which can't be transcluded into itself.
+pub(super) fn transclude<'a>(
+    events: Vec<(Event<'a>, Range<usize>)>,
+    markdown: &str,
+    dir: &Path,
+    id: Option<&str>,
+    diagnostics: &mut Vec<Diagnostic>,
+) -> Vec<(Event<'a>, Range<usize>)> {
+    let mut stack: Vec<String> = id.map(str::to_string).into_iter().collect();
+    let mut transcluded = Vec::with_capacity(events.len());
+    let mut events = events.into_iter();
+    while let Some((event, range)) = events.next() {
+        if !matches!(event, Event::Start(Tag::Paragraph)) {
+            transcluded.push((event, range));
+            continue;
+        }
+        let mut block = vec![(event, range.clone())];
+        for (event, range) in events.by_ref() {
+            let end = matches!(event, Event::End(TagEnd::Paragraph));
+            block.push((event, range));
+            if end {
+                break;
+            }
+        }
+        let block_events: Vec<Event> = block.iter().map(|(e, _)| e.clone()).collect();
+        let Some((id, heading)) = directive(&block_events) else {
+            transcluded.extend(block);
+            continue;
+        };
+
+        let mut problems = Vec::new();
+        match note_events(&id, heading.as_deref(), dir, &mut stack, &mut problems) {
+            Ok(note) => transcluded.extend(note.into_iter().map(|e| (e, range.clone()))),
+            Err(message) => {
+                problems.insert(0, message);
+                transcluded.extend(block);
+            }
+        }
+        let source = markdown[range.clone()].trim();
+        for message in problems {
+            diagnostics.push(Diagnostic::new(markdown, range.clone(), source, message));
+        }
+    }
+    transcluded
+}
+
+/// The note ID and heading of a paragraph holding only a transclusion
+/// directive.
+fn directive(paragraph: &[Event]) -> Option<(String, Option<String>)> {
+    static PATTERN: LazyLock<Regex> =
+        LazyLock::new(|| Regex::new(r"^!\[\[([A-Za-z0-9_-]+)(?:#([^\]]+))?\]\]$").unwrap());
+    let mut text = String::new();
+    for event in &paragraph[1..paragraph.len() - 1] {
+        match event {
+            Event::Text(t) => text.push_str(t),
+            _ => return None,
+        }
+    }
+    let captures = PATTERN.captures(text.trim())?;
+    let heading = captures.get(2).map(|m| m.as_str().trim().to_string());
+    Some((captures[1].to_string(), heading))
+}
+
+/// Events of a note, or of a section of it, with the notes transcluded
+/// into it expanded. `stack` holds the notes being transcluded, to catch
+/// cycles. Problems with the notes transcluded into this one are added
+/// to `problems`.
+fn note_events(
+    id: &str,
+    heading: Option<&str>,
+    dir: &Path,
+    stack: &mut Vec<String>,
+    problems: &mut Vec<String>,
+) -> Result<Vec<Event<'static>>, String> {
+    if stack.iter().any(|note| note == id) {
+        return Err(format!(
+            "note `{}` transcludes itself ({} → {})",
+            id,
+            stack.join(" → "),
+            id
+        ));
+    }
+    if stack.len() >= MAX_DEPTH {
+        return Err(format!(
+            "note `{}` is transcluded more than {} notes deep",
+            id, MAX_DEPTH
+        ));
+    }
+    let source = read_to_string(dir.join(format!("{}.md", id)))
+        .map_err(|e| format!("could not transclude note `{}`: {}", id, e))?;
+
+    // Leave out the note's metadata
+    let mut events = Vec::new();
+    let mut metadata = false;
+    for event in Markdown(source.as_str()).parser() {
+        match event {
+            Event::Start(Tag::MetadataBlock(_)) => metadata = true,
+            Event::End(TagEnd::MetadataBlock(_)) => metadata = false,
+            event if !metadata => events.push(into_static(event)),
+            _ => {}
+        }
+    }
+    if let Some(heading) = heading {
+        events = section(events, heading)
+            .ok_or_else(|| format!("note `{}` has no heading `{}`", id, heading))?;
+    }
+
+    // Expand the notes transcluded into this one
+    stack.push(id.to_string());
+    let mut expanded = Vec::with_capacity(events.len());
+    let mut start = None;
+    for (i, event) in events.iter().enumerate() {
+        match event {
+            Event::Start(Tag::Paragraph) => start = Some(i),
+            Event::End(TagEnd::Paragraph) => {
+                let paragraph = &events[start.take().unwrap_or(i)..=i];
+                let nested = directive(paragraph).map(|(nested, heading)| {
+                    note_events(&nested, heading.as_deref(), dir, stack, problems)
+                });
+                match nested {
+                    Some(Ok(note)) => expanded.extend(note),
+                    Some(Err(message)) => {
+                        problems.push(format!("in note `{}`: {}", id, message));
+                        expanded.extend(paragraph.iter().cloned());
+                    }
+                    None => expanded.extend(paragraph.iter().cloned()),
+                }
+            }
+            _ if start.is_none() => expanded.push(event.clone()),
+            _ => {}
+        }
+    }
+    stack.pop();
+    Ok(expanded)
+}
+
+/// The events of the section under a heading, matched by its text (ignoring
+/// case), its slug or its ID, up to the next heading of the same or a
+/// higher level.
+fn section(events: Vec<Event<'static>>, heading: &str) -> Option<Vec<Event<'static>>> {
+    let mut start = None;
+    let mut text = String::new();
+    for (i, event) in events.iter().enumerate() {
+        match event {
+            Event::Start(Tag::Heading { id, .. }) => {
+                if id.as_deref() == Some(heading) {
+                    start = Some(i);
+                    break;
+                }
+                text.clear();
+            }
+            Event::Text(t) | Event::Code(t) => text.push_str(t),
+            Event::End(TagEnd::Heading(_))
+                if text.trim().eq_ignore_ascii_case(heading) || slugify(&text) == heading =>
+            {
+                start = events[..i]
+                    .iter()
+                    .rposition(|e| matches!(e, Event::Start(Tag::Heading { .. })));
+                break;
+            }
+            _ => {}
+        }
+    }
+    let start = start?;
+    let Event::Start(Tag::Heading { level, .. }) = events[start] else {
+        unreachable!()
+    };
+    let end = events[start + 1..]
+        .iter()
+        .position(|e| matches!(e, Event::Start(Tag::Heading { level: l, .. }) if *l <= level))
+        .map_or(events.len(), |i| start + 1 + i);
+    Some(events[start..end].to_vec())
+}
+
+fn owned(s: CowStr) -> CowStr<'static> {
+    CowStr::from(s.into_string())
+}
+
+/// Copy an event so that it no longer borrows from the markdown it was
+/// parsed from.
+fn into_static(event: Event) -> Event<'static> {
+    match event {
+        Event::Start(tag) => Event::Start(match tag {
+            Tag::Paragraph => Tag::Paragraph,
+            Tag::Heading {
+                level,
+                id,
+                classes,
+                attrs,
+            } => Tag::Heading {
+                level,
+                id: id.map(owned),
+                classes: classes.into_iter().map(owned).collect(),
+                attrs: attrs
+                    .into_iter()
+                    .map(|(key, value)| (owned(key), value.map(owned)))
+                    .collect(),
+            },
+            Tag::BlockQuote(kind) => Tag::BlockQuote(kind),
+            Tag::CodeBlock(CodeBlockKind::Fenced(info)) => {
+                Tag::CodeBlock(CodeBlockKind::Fenced(owned(info)))
+            }
+            Tag::CodeBlock(CodeBlockKind::Indented) => Tag::CodeBlock(CodeBlockKind::Indented),
+            Tag::HtmlBlock => Tag::HtmlBlock,
+            Tag::List(start) => Tag::List(start),
+            Tag::Item => Tag::Item,
+            Tag::FootnoteDefinition(label) => Tag::FootnoteDefinition(owned(label)),
+            Tag::Table(alignments) => Tag::Table(alignments),
+            Tag::TableHead => Tag::TableHead,
+            Tag::TableRow => Tag::TableRow,
+            Tag::TableCell => Tag::TableCell,
+            Tag::Emphasis => Tag::Emphasis,
+            Tag::Strong => Tag::Strong,
+            Tag::Strikethrough => Tag::Strikethrough,
+            Tag::Link {
+                link_type,
+                dest_url,
+                title,
+                id,
+            } => Tag::Link {
+                link_type,
+                dest_url: owned(dest_url),
+                title: owned(title),
+                id: owned(id),
+            },
+            Tag::Image {
+                link_type,
+                dest_url,
+                title,
+                id,
+            } => Tag::Image {
+                link_type,
+                dest_url: owned(dest_url),
+                title: owned(title),
+                id: owned(id),
+            },
+            Tag::MetadataBlock(kind) => Tag::MetadataBlock(kind),
+        }),
+        Event::End(end) => Event::End(end),
+        Event::Text(text) => Event::Text(owned(text)),
+        Event::Code(code) => Event::Code(owned(code)),
+        Event::InlineMath(math) => Event::InlineMath(owned(math)),
+        Event::DisplayMath(math) => Event::DisplayMath(owned(math)),
+        Event::Html(html) => Event::Html(owned(html)),
+        Event::InlineHtml(html) => Event::InlineHtml(owned(html)),
+        Event::FootnoteReference(label) => Event::FootnoteReference(owned(label)),
+        Event::SoftBreak => Event::SoftBreak,
+        Event::HardBreak => Event::HardBreak,
+        Event::Rule => Event::Rule,
+        Event::TaskListMarker(checked) => Event::TaskListMarker(checked),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_dir::TestDir;
+
+    /// Text of a note after transcluding notes from `dir`, and the
+    /// diagnostics
+    fn render(dir: &TestDir, id: Option<&str>, markdown: &str) -> (String, Vec<String>) {
+        let parsed = Markdown(markdown);
+        let events = parsed.parser().into_offset_iter().collect();
+        let mut diagnostics = Vec::new();
+        let events = transclude(events, markdown, dir.path(), id, &mut diagnostics);
+        let mut text = String::new();
+        for (event, _) in events {
+            match event {
+                Event::Text(t) => text.push_str(&t),
+                Event::End(TagEnd::Paragraph | TagEnd::Heading(_)) => text.push('|'),
+                _ => {}
+            }
+        }
+        text.pop();
+        let messages = diagnostics.into_iter().map(|d| d.message).collect();
+        (text, messages)
+    }
+
+    #[test]
+    fn finds_directives() {
+        let paragraph = |text| {
+            vec![
+                Event::Start(Tag::Paragraph),
+                Event::Text(CowStr::Borrowed(text)),
+                Event::End(TagEnd::Paragraph),
+            ]
+        };
+        assert_eq!(
+            directive(&paragraph("![[protocol]]")),
+            Some(("protocol".to_string(), None))
+        );
+        assert_eq!(
+            directive(&paragraph("![[protocol# Set up ]]")),
+            Some(("protocol".to_string(), Some("Set up".to_string())))
+        );
+        assert_eq!(directive(&paragraph("see ![[protocol]]")), None);
+        assert_eq!(directive(&paragraph("![[data/run.csv]]")), None);
+    }
+
+    #[test]
+    fn transcludes_notes_and_sections() {
+        let notes = TestDir::new(&[(
+                "protocol.md",
+                "---\ntitle: Protocol\n---\nIntro\n\n## Set up\n\nCalibrate\n\n### Detail\n\nZero\n\n## Run\n\nMeasure\n",
+            )]);
+        let (text, problems) = render(&notes, Some("log"), "![[protocol#set-up]]\n");
+        assert_eq!(text, "Set up|Calibrate|Detail|Zero");
+        assert!(problems.is_empty());
+
+        let (text, _) = render(&notes, Some("log"), "![[protocol]]\n");
+        assert!(!text.contains("title"));
+        assert!(text.starts_with("Intro|Set up"));
+
+        let (text, problems) = render(&notes, Some("log"), "![[protocol#Nope]]\n");
+        assert_eq!(text, "![[protocol#Nope]]");
+        assert_eq!(problems, ["note `protocol` has no heading `Nope`"]);
+    }
+
+    #[test]
+    fn refuses_a_note_that_transcludes_itself() {
+        let notes = TestDir::new(&[("a.md", "A\n\n![[a]]\n")]);
+        let (text, problems) = render(&notes, Some("a"), "A\n\n![[a]]\n");
+        assert_eq!(text, "A|![[a]]");
+        assert_eq!(problems, ["note `a` transcludes itself (a → a)"]);
+    }
+
+    #[test]
+    fn refuses_cycles_of_notes() {
+        let notes = TestDir::new(&[("a.md", "A\n\n![[b]]\n"), ("b.md", "B\n\n![[a]]\n")]);
+        let (text, problems) = render(&notes, Some("a"), "A\n\n![[b]]\n");
+        assert_eq!(text, "A|B|![[a]]");
+        assert_eq!(
+            problems,
+            ["in note `b`: note `a` transcludes itself (a → b → a)"]
+        );
+    }
+
+    #[test]
+    fn limits_the_depth() {
+        let chain: Vec<(String, String)> = (0..=MAX_DEPTH)
+            .map(|i| (format!("n{}.md", i), format!("![[n{}]]\n", i + 1)))
+            .collect();
+        let chain: Vec<(&str, &str)> = chain.iter().map(|(a, b)| (&**a, &**b)).collect();
+        let notes = TestDir::new(&chain);
+        let (_, problems) = render(&notes, None, "![[n0]]\n");
+        assert_eq!(problems.len(), 1);
+        assert!(problems[0].ends_with(&format!(
+            "note `n{}` is transcluded more than {} notes deep",
+            MAX_DEPTH, MAX_DEPTH
+        )));
+    }
+}
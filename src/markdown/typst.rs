@@ -0,0 +1,1127 @@
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
+
+use pulldown_cmark::{Alignment, CodeBlockKind, CowStr, Event, LinkType, Tag, TagEnd};
+
+use super::{extract_footnotes, toc, TableCaption};
+
+/// Options controlling the typst output of `Markdown::render_typst_with`.
+#[derive(Clone, Debug, Default)]
+pub struct TypstOptions {
+    /// Write only the body of the document, without the `#set` rules at
+    /// the start, so that it can be `#include`d into another document
+    pub fragment: bool,
+
+    /// Directory that notes transcluded into the note, and CSV data it
+    /// includes, are read from. Without it, they are read relative to the
+    /// current directory.
+    pub data_dir: Option<PathBuf>,
+
+    /// ID of the note being rendered, so that a note which transcludes
+    /// itself is caught before any of it is inlined
+    pub note_id: Option<String>,
+}
+
+pub(super) struct TypstWriter<'a, 'o, W> {
+    /// Iterator supplying events.
+    iter: std::vec::IntoIter<Event<'a>>,
+
+    /// Writer to write to.
+    writer: W,
+
+    /// Options controlling the output.
+    opts: &'o TypstOptions,
+
+    /// Whether or not the last write wrote a newline.
+    end_newline: bool,
+
+    /// Number of spaces to indent each line by, to continue list items.
+    indent: Vec<usize>,
+    /// Whether the last write opened a list item or block quote, so that
+    /// a paragraph needn't be separated from what came before.
+    container_start: bool,
+    /// Number of the next item of each open list, or `None` for bullets.
+    lists: Vec<Option<u64>>,
+
+    /// Captions of the tables in the document, in order.
+    captions: std::vec::IntoIter<Option<TableCaption>>,
+    table_caption: Option<TableCaption>,
+    first_cell: bool,
+
+    /// Label of the heading being written.
+    heading_label: Option<CowStr<'a>>,
+    /// Labels of the headings and tables, which links within the note can
+    /// refer to.
+    labels: HashSet<String>,
+
+    /// Footnote definitions that have not yet been referenced.
+    footnotes: HashMap<CowStr<'a>, Vec<Event<'a>>>,
+    /// Footnotes that have been written, which later references point to.
+    written_footnotes: HashSet<CowStr<'a>>,
+
+    /// Warnings about content that could not be translated.
+    warnings: Vec<String>,
+}
+
+impl<'a, 'o, W> TypstWriter<'a, 'o, W>
+where
+    W: pulldown_cmark_escape::StrWrite,
+{
+    pub(super) fn new(mut events: Vec<Event<'a>>, writer: W, opts: &'o TypstOptions) -> Self {
+        let headings = toc::heading_ids(&mut events, |e| e, |e| e);
+        let (events, captions) = TableCaption::extract(events, |e| e);
+        let mut labels: HashSet<String> = headings.into_iter().map(|entry| entry.id).collect();
+        labels.extend(captions.iter().flatten().filter_map(|c| c.label.clone()));
+        let (events, footnotes) = extract_footnotes(events);
+        Self {
+            iter: events.into_iter(),
+            writer,
+            opts,
+            end_newline: true,
+            indent: Vec::new(),
+            container_start: false,
+            lists: Vec::new(),
+            captions: captions.into_iter(),
+            table_caption: None,
+            first_cell: false,
+            heading_label: None,
+            labels,
+            footnotes,
+            written_footnotes: HashSet::new(),
+            warnings: Vec::new(),
+        }
+    }
+
+    /// Writes a new line.
+    fn write_newline(&mut self) -> Result<(), W::Error> {
+        self.write("\n")
+    }
+
+    /// Writes a buffer, indenting each new line to the depth of the
+    /// enclosing list items, and tracks whether or not a newline was
+    /// written.
+    fn write(&mut self, s: &str) -> Result<(), W::Error> {
+        for line in s.split_inclusive('\n') {
+            if self.end_newline && line != "\n" {
+                let indent: usize = self.indent.iter().sum();
+                self.writer.write_str(&" ".repeat(indent))?;
+            }
+            self.writer.write_str(line)?;
+            self.end_newline = line.ends_with('\n');
+            self.container_start = false;
+        }
+        Ok(())
+    }
+
+    /// Separates a block from what came before it with a blank line.
+    fn write_block_start(&mut self) -> Result<(), W::Error> {
+        if self.container_start {
+            Ok(())
+        } else if self.end_newline {
+            self.write_newline()
+        } else {
+            self.write("\n\n")
+        }
+    }
+
+    /// Writes the document, returning warnings about any content that
+    /// could not be translated.
+    pub(super) fn run(mut self) -> Result<Vec<String>, W::Error> {
+        if !self.opts.fragment {
+            self.write("#set page(paper: \"a4\")\n")?;
+        }
+        while let Some(event) = self.iter.next() {
+            self.event(event)?;
+        }
+        if !self.end_newline {
+            self.write_newline()?;
+        }
+        Ok(self.warnings)
+    }
+
+    /// Writes a single event.
+    fn event(&mut self, event: Event<'a>) -> Result<(), W::Error> {
+        match event {
+            Event::Start(tag) => self.start_tag(tag)?,
+            Event::End(tag) => self.end_tag(tag)?,
+            Event::Text(text) => {
+                let at_line_start = self.end_newline || self.container_start;
+                self.write(&escape_typst(&text, at_line_start))?;
+            }
+            Event::Code(text) => {
+                if text.contains('`') {
+                    self.write(&format!("#raw(\"{}\")", escape_string(&text)))?;
+                } else {
+                    self.write(&format!("`{}`", text))?;
+                }
+            }
+            Event::InlineMath(text) => match translate_math(&text) {
+                Ok(math) => self.write(&format!("${}$", math.trim()))?,
+                Err(e) => {
+                    self.math_warning(&text, &e);
+                    self.write(&format!(
+                        "#raw(lang: \"latex\", \"{}\")",
+                        escape_string(&text)
+                    ))?;
+                }
+            },
+            Event::DisplayMath(text) => {
+                if !self.end_newline {
+                    self.write_newline()?;
+                }
+                match translate_math(&text) {
+                    Ok(math) => self.write(&format!("$ {} $\n", math.trim()))?,
+                    Err(e) => {
+                        self.math_warning(&text, &e);
+                        self.write(&format!(
+                            "#raw(block: true, lang: \"latex\", \"{}\")\n",
+                            escape_string(text.trim())
+                        ))?;
+                    }
+                }
+            }
+            Event::Html(html) | Event::InlineHtml(html) => self.write_html(&html)?,
+            Event::SoftBreak => self.write_newline()?,
+            Event::HardBreak => self.write(" \\\n")?,
+            Event::Rule => {
+                self.write_block_start()?;
+                self.write("#line(length: 100%)\n")?;
+            }
+            Event::FootnoteReference(name) => {
+                let label = footnote_label(&name);
+                match self.footnotes.remove(&name) {
+                    Some(definition) => {
+                        self.write("#footnote[")?;
+                        self.write_footnote(definition)?;
+                        self.write(&format!("]<{}>", label))?;
+                        self.written_footnotes.insert(name);
+                    }
+                    None if self.written_footnotes.contains(&name) => {
+                        self.write(&format!("#footnote(<{}>)", label))?;
+                    }
+                    None => {
+                        self.warnings
+                            .push(format!("footnote `{}` is not defined", name));
+                    }
+                }
+            }
+            Event::TaskListMarker(true) => self.write("☒ ")?,
+            Event::TaskListMarker(false) => self.write("☐ ")?,
+        }
+        Ok(())
+    }
+
+    /// Records a warning about math that could not be translated.
+    fn math_warning(&mut self, math: &str, reason: &str) {
+        self.warnings.push(format!(
+            "math `{}` could not be translated to typst ({}), so it is written as raw latex",
+            math.trim(),
+            reason
+        ));
+    }
+
+    /// Writes the body of a footnote definition.
+    fn write_footnote(&mut self, definition: Vec<Event<'a>>) -> Result<(), W::Error> {
+        let mut events = definition.into_iter().peekable();
+        // Don't start a new paragraph at the beginning of the footnote
+        if let Some(Event::Start(Tag::Paragraph)) = events.peek() {
+            events.next();
+        }
+        for event in events {
+            self.event(event)?;
+        }
+        Ok(())
+    }
+
+    /// Writes html as a typst comment, since typst has no equivalent.
+    fn write_html(&mut self, html: &str) -> Result<(), W::Error> {
+        let trimmed = html.trim();
+        if trimmed.is_empty() {
+            return Ok(());
+        }
+        if !trimmed.starts_with("<!--") && !trimmed.starts_with("</") {
+            self.warnings.push(format!(
+                "html `{}` could not be translated to typst",
+                trimmed
+            ));
+        }
+        self.write(&format!("/* {} */", trimmed.replace("*/", "* /")))?;
+        if html.ends_with('\n') {
+            self.write_newline()?;
+        }
+        Ok(())
+    }
+
+    fn start_tag(&mut self, tag: Tag<'a>) -> Result<(), W::Error> {
+        match tag {
+            Tag::Paragraph => {
+                if let Some(len) = toc::marker_len(self.iter.as_slice(), |e| e) {
+                    self.iter.nth(len - 1);
+                    self.write_block_start()?;
+                    return self.write("#outline()\n");
+                }
+                self.write_block_start()
+            }
+            Tag::Heading { level, id, .. } => {
+                if !self.end_newline {
+                    self.write_newline()?;
+                }
+                self.heading_label = id;
+                self.write(&format!("\n{} ", "=".repeat(level as usize)))
+            }
+            Tag::Table(alignments) => {
+                self.write_block_start()?;
+                self.table_caption = self.captions.next().flatten();
+                if self.table_caption.is_some() {
+                    self.write("#figure(\n  table(\n")?;
+                    self.indent.push(4);
+                } else {
+                    self.write("#table(\n")?;
+                    self.indent.push(2);
+                }
+                let align: Vec<&str> = alignments
+                    .iter()
+                    .map(|alignment| match alignment {
+                        Alignment::Center => "center",
+                        Alignment::Right => "right",
+                        _ => "left",
+                    })
+                    .collect();
+                // A single alignment needs a trailing comma to make an array
+                let trailing = if align.len() == 1 { "," } else { "" };
+                self.write(&format!(
+                    "columns: {},\nalign: ({}{}),\n",
+                    alignments.len(),
+                    align.join(", "),
+                    trailing
+                ))
+            }
+            Tag::TableHead => {
+                self.first_cell = true;
+                self.write("table.header(")
+            }
+            Tag::TableRow => {
+                self.first_cell = true;
+                Ok(())
+            }
+            Tag::TableCell => {
+                if std::mem::take(&mut self.first_cell) {
+                    self.write("[")
+                } else {
+                    self.write(" [")
+                }
+            }
+            Tag::BlockQuote(_) => {
+                self.write_block_start()?;
+                self.write("#quote(block: true)[\n")?;
+                self.container_start = true;
+                Ok(())
+            }
+            Tag::CodeBlock(info) => {
+                self.write_block_start()?;
+                let lang = match info {
+                    CodeBlockKind::Fenced(info) => info.split(' ').next().unwrap().to_string(),
+                    CodeBlockKind::Indented => String::new(),
+                };
+                let code = self.take_text();
+                // The fence must be longer than any run of backticks in the code
+                let longest = code.split(|c| c != '`').map(str::len).max().unwrap_or(0);
+                let fence = "`".repeat(longest.max(2) + 1);
+                self.write(&format!("{}{}\n", fence, lang))?;
+                self.write(&code)?;
+                if !self.end_newline {
+                    self.write_newline()?;
+                }
+                self.write(&format!("{}\n", fence))
+            }
+            Tag::List(start) => {
+                if self.lists.is_empty() {
+                    self.write_block_start()?;
+                } else if !self.end_newline {
+                    self.write_newline()?;
+                }
+                self.lists.push(start);
+                Ok(())
+            }
+            Tag::Item => {
+                if !self.end_newline {
+                    self.write_newline()?;
+                }
+                let marker = match self.lists.last_mut() {
+                    Some(Some(number)) => {
+                        *number += 1;
+                        format!("{}. ", *number - 1)
+                    }
+                    _ => "- ".to_string(),
+                };
+                self.write(&marker)?;
+                self.indent.push(marker.len());
+                self.container_start = true;
+                Ok(())
+            }
+            Tag::Emphasis => self.write("#emph["),
+            Tag::Strong => self.write("#strong["),
+            Tag::Strikethrough => self.write("#strike["),
+            Tag::Link {
+                link_type: LinkType::Email,
+                dest_url: dest,
+                ..
+            } => self.write(&format!("#link(\"mailto:{}\")[", escape_string(&dest))),
+            // A heading or table in this note, by its label
+            Tag::Link { dest_url: dest, .. }
+                if dest
+                    .strip_prefix('#')
+                    .is_some_and(|label| self.labels.contains(label)) =>
+            {
+                self.write(&format!("#link(<{}>)[", &dest[1..]))
+            }
+            Tag::Link { dest_url: dest, .. } => {
+                self.write(&format!("#link(\"{}\")[", escape_string(&dest)))
+            }
+            Tag::Image { dest_url: dest, .. } => {
+                if dest.contains("://") {
+                    self.warnings.push(format!(
+                        "image `{}` is not a local file, which typst cannot include",
+                        dest
+                    ));
+                }
+                let alt = self.take_text();
+                if alt.is_empty() {
+                    self.write(&format!("#image(\"{}\")", escape_string(&dest)))
+                } else {
+                    self.write(&format!(
+                        "#image(\"{}\", alt: \"{}\")",
+                        escape_string(&dest),
+                        escape_string(&alt)
+                    ))
+                }
+            }
+            // Removed from the event stream by `extract_footnotes`
+            Tag::FootnoteDefinition(_) => {
+                self.take_text();
+                Ok(())
+            }
+            Tag::HtmlBlock => {
+                if !self.end_newline {
+                    self.write_newline()?;
+                }
+                Ok(())
+            }
+            // Front matter is parsed into `NoteMetadata`
+            Tag::MetadataBlock(_) => {
+                self.take_text();
+                Ok(())
+            }
+        }
+    }
+
+    fn end_tag(&mut self, tag: TagEnd) -> Result<(), W::Error> {
+        match tag {
+            TagEnd::Paragraph => Ok(()),
+            TagEnd::Heading(_) => {
+                if let Some(label) = self.heading_label.take() {
+                    self.write(&format!(" <{}>", label))?;
+                }
+                self.write_newline()
+            }
+            TagEnd::Table => match self.table_caption.take() {
+                Some(caption) => {
+                    self.indent.pop();
+                    self.write("  ),\n  caption: [")?;
+                    self.write(&escape_typst(&caption.text, false))?;
+                    self.write("],\n)")?;
+                    if let Some(label) = &caption.label {
+                        self.write(&format!(" <{}>", label))?;
+                    }
+                    self.write_newline()
+                }
+                None => {
+                    self.indent.pop();
+                    self.write(")\n")
+                }
+            },
+            TagEnd::TableHead => self.write("),\n"),
+            TagEnd::TableRow => self.write_newline(),
+            TagEnd::TableCell => self.write("],"),
+            TagEnd::BlockQuote => {
+                if !self.end_newline {
+                    self.write_newline()?;
+                }
+                self.write("]\n")
+            }
+            TagEnd::CodeBlock => Ok(()), // handled in start
+            TagEnd::List(_) => {
+                self.lists.pop();
+                Ok(())
+            }
+            TagEnd::Item => {
+                self.indent.pop();
+                if !self.end_newline {
+                    self.write_newline()?;
+                }
+                Ok(())
+            }
+            TagEnd::Emphasis | TagEnd::Strong | TagEnd::Strikethrough | TagEnd::Link => {
+                self.write("]")
+            }
+            TagEnd::Image => Ok(()),              // handled in start
+            TagEnd::FootnoteDefinition => Ok(()), // handled in start
+            TagEnd::HtmlBlock => {
+                if !self.end_newline {
+                    self.write_newline()?;
+                }
+                Ok(())
+            }
+            TagEnd::MetadataBlock(_) => Ok(()), // handled in start
+        }
+    }
+
+    /// Consumes events up to the end of the current tag, returning the text
+    /// they contain.
+    fn take_text(&mut self) -> String {
+        let mut text = String::new();
+        let mut nest = 0;
+        for event in self.iter.by_ref() {
+            match event {
+                Event::Start(_) => nest += 1,
+                Event::End(_) => {
+                    if nest == 0 {
+                        break;
+                    }
+                    nest -= 1;
+                }
+                Event::Text(t) | Event::Code(t) => text.push_str(&t),
+                Event::InlineMath(t) | Event::DisplayMath(t) => {
+                    text.push('$');
+                    text.push_str(&t);
+                    text.push('$');
+                }
+                Event::SoftBreak | Event::HardBreak => text.push(' '),
+                _ => {}
+            }
+        }
+        text
+    }
+}
+
+/// Escapes the characters of `text` that are special in typst markup. If
+/// the text starts a line, markers that would start a heading or list
+/// item are escaped as well.
+fn escape_typst(text: &str, at_line_start: bool) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    let mut chars = text.chars().peekable();
+    let mut line_start = at_line_start;
+    while let Some(c) = chars.next() {
+        let special = match c {
+            '\\' | '*' | '_' | '`' | '$' | '#' | '@' | '<' | '[' | ']' | '~' => true,
+            // `//` and `/*` start comments, and `/` at the start of a line
+            // starts a term
+            '/' => line_start || matches!(chars.peek(), Some('/' | '*')),
+            '=' | '-' | '+' => line_start,
+            _ => false,
+        };
+        if special {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+        // A number followed by `.` at the start of a line is a numbered item
+        if line_start && c.is_ascii_digit() {
+            while let Some(&digit) = chars.peek().filter(|d| d.is_ascii_digit()) {
+                escaped.push(digit);
+                chars.next();
+            }
+            if chars.peek() == Some(&'.') {
+                escaped.push('\\');
+            }
+        }
+        if c == '\n' {
+            line_start = true;
+        } else if !c.is_whitespace() {
+            line_start = false;
+        }
+    }
+    escaped
+}
+
+/// Escapes `text` for use in a typst string literal.
+fn escape_string(text: &str) -> String {
+    text.replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Label of a footnote, which later references to it point to.
+fn footnote_label(name: &str) -> String {
+    let name: String = name
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '-' })
+        .collect();
+    format!("fn-{}", name)
+}
+
+/// Translates latex math into typst math, returning the reason if the math
+/// uses a command or environment that has no translation.
+pub(super) fn translate_math(latex: &str) -> Result<String, String> {
+    let mut translator = MathTranslator {
+        src: latex,
+        pos: 0,
+        depth: 0,
+    };
+    match translator.sequence(false)? {
+        (math, Stop::Eof) => Ok(math),
+        (_, Stop::Brace) => Err("unbalanced `}`".to_string()),
+        (_, Stop::End(env)) => Err(format!("`\\end{{{}}}` without `\\begin`", env)),
+        (_, Stop::Cell | Stop::Row) => unreachable!(),
+    }
+}
+
+/// What ended a sequence of latex math.
+enum Stop {
+    /// The end of the math
+    Eof,
+    /// A closing `}`
+    Brace,
+    /// A `&` between the cells of a matrix
+    Cell,
+    /// A `\\` between the rows of a matrix
+    Row,
+    /// An `\end{..}` of an environment
+    End(String),
+}
+
+struct MathTranslator<'s> {
+    src: &'s str,
+    pos: usize,
+    /// Number of typst function calls the translation is inside, where
+    /// `,`, `;` and `:` would separate arguments.
+    depth: usize,
+}
+
+impl MathTranslator<'_> {
+    fn peek(&self) -> Option<char> {
+        self.src[self.pos..].chars().next()
+    }
+
+    fn next_char(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.pos += c.len_utf8();
+        Some(c)
+    }
+
+    fn skip_whitespace(&mut self) {
+        while self.peek().is_some_and(char::is_whitespace) {
+            self.next_char();
+        }
+    }
+
+    /// Reads the name of a command, after its `\`.
+    fn command_name(&mut self) -> &str {
+        let start = self.pos;
+        while self.peek().is_some_and(|c| c.is_ascii_alphabetic()) {
+            self.next_char();
+        }
+        if self.pos == start {
+            self.next_char();
+        }
+        &self.src[start..self.pos]
+    }
+
+    /// Reads the raw text of a `{..}` argument, such as an environment name.
+    fn raw_argument(&mut self) -> Result<&str, String> {
+        self.skip_whitespace();
+        if self.next_char() != Some('{') {
+            return Err("missing `{` after command".to_string());
+        }
+        let start = self.pos;
+        let mut nest = 0;
+        loop {
+            match self.next_char() {
+                Some('{') => nest += 1,
+                Some('}') if nest == 0 => return Ok(&self.src[start..self.pos - 1]),
+                Some('}') => nest -= 1,
+                Some('\\') => {
+                    self.next_char();
+                }
+                Some(_) => {}
+                None => return Err("unbalanced `{`".to_string()),
+            }
+        }
+    }
+
+    /// Translates the argument of a command: either a `{..}` group, or a
+    /// single character or command.
+    fn argument(&mut self) -> Result<String, String> {
+        self.skip_whitespace();
+        match self.next_char() {
+            Some('{') => self.group(),
+            Some('\\') => {
+                let name = self.command_name().to_string();
+                self.command(&name)
+            }
+            Some(c) => Ok(self.character(c)),
+            None => Err("missing argument".to_string()),
+        }
+    }
+
+    /// Translates the rest of a group, after its `{`.
+    fn group(&mut self) -> Result<String, String> {
+        match self.sequence(false)? {
+            (math, Stop::Brace) => Ok(math),
+            _ => Err("unbalanced `{`".to_string()),
+        }
+    }
+
+    /// Translates math up to the end of the current group or environment.
+    /// If `cells` is true, `&` and `\\` end the sequence, to separate the
+    /// cells of a matrix.
+    fn sequence(&mut self, cells: bool) -> Result<(String, Stop), String> {
+        let mut math = String::new();
+        while let Some(c) = self.next_char() {
+            match c {
+                '}' => return Ok((math, Stop::Brace)),
+                '{' => {
+                    let group = self.group()?;
+                    push_math(&mut math, &group);
+                }
+                '&' if cells => return Ok((math, Stop::Cell)),
+                '^' | '_' => {
+                    let script = self.argument()?;
+                    math.push(c);
+                    let simple = !script.is_empty()
+                        && (script.chars().all(|c| c.is_ascii_digit())
+                            || script.chars().all(|c| c.is_alphabetic() || c == '.'));
+                    if simple {
+                        math.push_str(&script);
+                    } else {
+                        math.push('(');
+                        math.push_str(&script);
+                        math.push(')');
+                    }
+                }
+                '\\' => {
+                    let name = self.command_name().to_string();
+                    match name.as_str() {
+                        "\\" if cells => return Ok((math, Stop::Row)),
+                        "end" => return Ok((math, Stop::End(self.raw_argument()?.to_string()))),
+                        _ => {
+                            let command = self.command(&name)?;
+                            push_math(&mut math, &command);
+                        }
+                    }
+                }
+                c if c.is_whitespace() => {}
+                c => {
+                    let c = self.character(c);
+                    push_math(&mut math, &c);
+                }
+            }
+        }
+        Ok((math, Stop::Eof))
+    }
+
+    /// Translates a single character.
+    fn character(&self, c: char) -> String {
+        match c {
+            ',' | ';' | ':' if self.depth > 0 => format!("\\{}", c),
+            '/' | '"' | '#' | '$' | '@' | '`' => format!("\\{}", c),
+            '~' => "space".to_string(),
+            c => c.to_string(),
+        }
+    }
+
+    /// Translates a call of a typst function, with arguments taken from
+    /// the latex.
+    fn call(&mut self, function: &str, args: usize) -> Result<String, String> {
+        self.depth += 1;
+        let args = (0..args)
+            .map(|_| self.argument())
+            .collect::<Result<Vec<_>, _>>()?;
+        self.depth -= 1;
+        Ok(format!("{}({})", function, args.join(", ")))
+    }
+
+    /// Translates a command, after its name.
+    fn command(&mut self, name: &str) -> Result<String, String> {
+        let function = match name {
+            "frac" | "dfrac" | "tfrac" | "cfrac" => return self.call("frac", 2),
+            "binom" | "dbinom" | "tbinom" => return self.call("binom", 2),
+            "sqrt" => {
+                self.skip_whitespace();
+                if self.peek() != Some('[') {
+                    return self.call("sqrt", 1);
+                }
+                self.next_char();
+                let end = self.src[self.pos..]
+                    .find(']')
+                    .ok_or("unbalanced `[`".to_string())?;
+                let index = translate_math(&self.src[self.pos..self.pos + end])?;
+                self.pos += end + 1;
+                self.depth += 1;
+                let radicand = self.argument()?;
+                self.depth -= 1;
+                return Ok(format!("root({}, {})", index, radicand));
+            }
+            "text" | "textrm" | "textnormal" | "mbox" | "hbox" => {
+                return Ok(format!("\"{}\"", escape_string(self.raw_argument()?)));
+            }
+            "textbf" => return Ok(format!("bold(\"{}\")", escape_string(self.raw_argument()?))),
+            "textit" => {
+                return Ok(format!(
+                    "italic(\"{}\")",
+                    escape_string(self.raw_argument()?)
+                ));
+            }
+            "operatorname" => {
+                return Ok(format!("op(\"{}\")", escape_string(self.raw_argument()?)));
+            }
+            "begin" => return self.environment(),
+            "label" | "tag" => {
+                self.raw_argument()?;
+                return Ok(String::new());
+            }
+            "left" | "right" | "middle" | "big" | "Big" | "bigg" | "Bigg" | "bigl" | "bigr"
+            | "Bigl" | "Bigr" | "biggl" | "biggr" | "Biggl" | "Biggr" => {
+                // The delimiter follows as a separate token; typst scales
+                // matching delimiters by itself
+                self.skip_whitespace();
+                if self.peek() == Some('.') {
+                    self.next_char();
+                }
+                return Ok(String::new());
+            }
+            "hat" | "widehat" => "hat",
+            "tilde" | "widetilde" => "tilde",
+            "bar" => "macron",
+            "vec" | "overrightarrow" => "arrow",
+            "dot" => "dot",
+            "ddot" => "dot.double",
+            "breve" => "breve",
+            "check" => "caron",
+            "acute" => "acute",
+            "grave" => "grave",
+            "overline" => "overline",
+            "underline" => "underline",
+            "mathbf" | "boldsymbol" | "bm" => "bold",
+            "mathrm" => "upright",
+            "mathit" => "italic",
+            "mathcal" | "mathscr" => "cal",
+            "mathbb" => "bb",
+            "mathsf" => "sans",
+            "mathtt" => "mono",
+            "mathfrak" => "frak",
+            name => {
+                return math_symbol(name)
+                    .map(String::from)
+                    .ok_or_else(|| format!("unsupported command `\\{}`", name));
+            }
+        };
+        self.call(function, 1)
+    }
+
+    /// Translates an environment, after its `\begin`.
+    fn environment(&mut self) -> Result<String, String> {
+        let name = self.raw_argument()?.to_string();
+        let delim = match name.as_str() {
+            "matrix" | "smallmatrix" | "array" => "#none",
+            "pmatrix" => "\"(\"",
+            "bmatrix" => "\"[\"",
+            "Bmatrix" => "\"{\"",
+            "vmatrix" => "\"|\"",
+            "Vmatrix" => "\"||\"",
+            "cases" => {
+                let rows: Vec<String> =
+                    self.rows(&name)?.iter().map(|row| row.join(" &")).collect();
+                return Ok(format!("cases({})", rows.join(", ")));
+            }
+            "aligned" | "align" | "align*" | "alignat" | "alignat*" | "alignedat" | "gather"
+            | "gather*" | "gathered" | "split" | "equation" | "equation*" | "multline"
+            | "multline*" | "flalign" | "flalign*" => {
+                if name.starts_with("alignat") || name == "alignedat" {
+                    // Number of columns
+                    self.raw_argument()?;
+                }
+                let mut lines = Vec::new();
+                loop {
+                    match self.sequence(true)? {
+                        (math, Stop::Cell) => lines.push(format!("{} &", math)),
+                        (math, Stop::Row) => lines.push(format!("{} \\\n", math)),
+                        (math, Stop::End(end)) if end == name => {
+                            lines.push(math);
+                            break;
+                        }
+                        _ => return Err(format!("missing `\\end{{{}}}`", name)),
+                    }
+                }
+                return Ok(lines.concat());
+            }
+            _ => return Err(format!("unsupported environment `{}`", name)),
+        };
+        if name == "array" {
+            // Column specification
+            self.raw_argument()?;
+        }
+        let rows: Vec<String> = self.rows(&name)?.iter().map(|row| row.join(", ")).collect();
+        Ok(format!("mat(delim: {}, {})", delim, rows.join("; ")))
+    }
+
+    /// Translates the cells of a matrix-like environment, row by row.
+    fn rows(&mut self, name: &str) -> Result<Vec<Vec<String>>, String> {
+        self.depth += 1;
+        let mut rows = vec![Vec::new()];
+        loop {
+            let (cell, stop) = self.sequence(true)?;
+            rows.last_mut().unwrap().push(cell.trim().to_string());
+            match stop {
+                Stop::Cell => {}
+                Stop::Row => rows.push(Vec::new()),
+                Stop::End(end) if end == name => break,
+                _ => return Err(format!("missing `\\end{{{}}}`", name)),
+            }
+        }
+        self.depth -= 1;
+        // Ignore a `\\` at the end of the last row
+        if rows.len() > 1 && rows.last().unwrap() == &[""] {
+            rows.pop();
+        }
+        Ok(rows)
+    }
+}
+
+/// Appends a translated token to typst math, separating it from the last
+/// token with a space if they would otherwise run together into a single
+/// identifier.
+fn push_math(math: &mut String, token: &str) {
+    if let (Some(last), Some(first)) = (math.chars().last(), token.chars().next()) {
+        let digits = last.is_ascii_digit() && first.is_ascii_digit();
+        if (last.is_alphanumeric() && (first.is_alphanumeric() && !digits))
+            || (last.is_alphabetic() && first == '.')
+        {
+            math.push(' ');
+        }
+    }
+    math.push_str(token);
+}
+
+/// The typst equivalent of a latex symbol command, if there is one.
+fn math_symbol(name: &str) -> Option<&str> {
+    Some(match name {
+        // Greek letters, where typst uses the same names as latex
+        "alpha" => "alpha",
+        "beta" => "beta",
+        "gamma" => "gamma",
+        "delta" => "delta",
+        "zeta" => "zeta",
+        "eta" => "eta",
+        "theta" => "theta",
+        "iota" => "iota",
+        "kappa" => "kappa",
+        "lambda" => "lambda",
+        "mu" => "mu",
+        "nu" => "nu",
+        "xi" => "xi",
+        "pi" => "pi",
+        "rho" => "rho",
+        "sigma" => "sigma",
+        "tau" => "tau",
+        "upsilon" => "upsilon",
+        "chi" => "chi",
+        "psi" => "psi",
+        "omega" => "omega",
+        "Gamma" => "Gamma",
+        "Delta" => "Delta",
+        "Theta" => "Theta",
+        "Lambda" => "Lambda",
+        "Xi" => "Xi",
+        "Pi" => "Pi",
+        "Sigma" => "Sigma",
+        "Upsilon" => "Upsilon",
+        "Phi" => "Phi",
+        "Psi" => "Psi",
+        "Omega" => "Omega",
+        // Latex and typst disagree on which form is the default
+        "epsilon" => "epsilon.alt",
+        "varepsilon" => "epsilon",
+        "phi" => "phi.alt",
+        "varphi" => "phi",
+        "vartheta" => "theta.alt",
+        "varpi" => "pi.alt",
+        "varrho" => "rho.alt",
+        "varsigma" => "sigma.alt",
+        "varkappa" => "kappa.alt",
+        // Operators, which typst knows by the same names
+        "sin" | "cos" | "tan" | "cot" | "sec" | "csc" | "sinh" | "cosh" | "tanh" | "coth"
+        | "arcsin" | "arccos" | "arctan" | "log" | "ln" | "lg" | "exp" | "lim" | "liminf"
+        | "limsup" | "max" | "min" | "sup" | "inf" | "det" | "dim" | "ker" | "deg" | "gcd"
+        | "arg" | "Pr" => name,
+        "bmod" => "mod",
+        "sum" => "sum",
+        "prod" => "product",
+        "coprod" => "product.co",
+        "int" => "integral",
+        "iint" => "integral.double",
+        "iiint" => "integral.triple",
+        "oint" => "integral.cont",
+        "bigcup" => "union.big",
+        "bigcap" => "sect.big",
+        "bigoplus" => "plus.circle.big",
+        "bigotimes" => "times.circle.big",
+        // Relations
+        "leq" | "le" => "<=",
+        "geq" | "ge" => ">=",
+        "neq" | "ne" => "!=",
+        "approx" => "approx",
+        "equiv" => "equiv",
+        "sim" => "tilde.op",
+        "simeq" => "tilde.eq",
+        "cong" => "tilde.equiv",
+        "propto" => "prop",
+        "ll" => "<<",
+        "gg" => ">>",
+        "in" => "in",
+        "notin" => "in.not",
+        "ni" => "in.rev",
+        "subset" => "subset",
+        "subseteq" => "subset.eq",
+        "supset" => "supset",
+        "supseteq" => "supset.eq",
+        "perp" => "perp",
+        "parallel" => "parallel",
+        "mid" => "divides",
+        // Arrows
+        "to" | "rightarrow" => "->",
+        "leftarrow" | "gets" => "<-",
+        "leftrightarrow" => "<->",
+        "Rightarrow" => "=>",
+        "Leftarrow" => "arrow.l.double",
+        "Leftrightarrow" | "iff" => "<=>",
+        "longrightarrow" => "-->",
+        "implies" => "==>",
+        "mapsto" => "|->",
+        "uparrow" => "arrow.t",
+        "downarrow" => "arrow.b",
+        // Binary operators
+        "pm" => "plus.minus",
+        "mp" => "minus.plus",
+        "times" => "times",
+        "div" => "div",
+        "cdot" => "dot.op",
+        "ast" => "ast",
+        "star" => "star",
+        "circ" => "compose",
+        "bullet" => "bullet",
+        "oplus" => "plus.circle",
+        "otimes" => "times.circle",
+        "cup" => "union",
+        "cap" => "sect",
+        "setminus" => "without",
+        "wedge" | "land" => "and",
+        "vee" | "lor" => "or",
+        "neg" | "lnot" => "not",
+        // Other symbols
+        "infty" => "infinity",
+        "partial" => "diff",
+        "nabla" => "nabla",
+        "hbar" => "planck.reduce",
+        "ell" => "ell",
+        "emptyset" | "varnothing" => "emptyset",
+        "forall" => "forall",
+        "exists" => "exists",
+        "prime" => "prime",
+        "dagger" => "dagger",
+        "aleph" => "aleph",
+        "Re" => "Re",
+        "Im" => "Im",
+        "cdots" => "dots.h.c",
+        "ldots" | "dots" => "dots.h",
+        "vdots" => "dots.v",
+        "ddots" => "dots.down",
+        // Delimiters
+        "langle" => "angle.l",
+        "rangle" => "angle.r",
+        "lfloor" => "floor.l",
+        "rfloor" => "floor.r",
+        "lceil" => "ceil.l",
+        "rceil" => "ceil.r",
+        "vert" | "lvert" | "rvert" => "|",
+        "Vert" | "lVert" | "rVert" | "|" => "||",
+        "{" => "\\{",
+        "}" => "\\}",
+        // Spacing
+        "," => "thin",
+        ":" | ">" => "med",
+        ";" => "thick",
+        " " => "space",
+        "quad" => "quad",
+        "qquad" => "wide",
+        "!" | "displaystyle" | "textstyle" | "scriptstyle" | "limits" | "nolimits" | "nonumber"
+        | "notag" => "",
+        // Escaped characters
+        "%" => "%",
+        "&" => "\\&",
+        "#" => "\\#",
+        "$" => "\\$",
+        "_" => "\\_",
+        "\\" => " \\ ",
+        _ => return None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::markdown::Markdown;
+
+    #[test]
+    fn translates_math() {
+        let cases = [
+            (r"\frac{a}{b} + x^{2n} - y_1", "frac(a, b)+x^(2 n)-y_1"),
+            (r"\sqrt[3]{x} \leq \alpha", "root(3, x)<=alpha"),
+            (r"\sin x \cdot \epsilon", "sin x dot.op epsilon.alt"),
+            (r"\mathbb{R}^n", "bb(R)^n"),
+            (r"\left( x \right)", "(x)"),
+            // Tokens that would run together are kept apart
+            (r"ab \alpha", "a b alpha"),
+            (r"12\cdot 3", "12 dot.op 3"),
+            // Commas inside a call would separate its arguments
+            (r"\frac{a,b}{c}", r"frac(a\,b, c)"),
+            (r#"\text{a "b"}"#, r#""a \"b\"""#),
+            (
+                r"\begin{pmatrix} a & b \\ c & d \end{pmatrix}",
+                "mat(delim: \"(\", a, b; c, d)",
+            ),
+            (
+                r"\begin{cases} 1 & x > 0 \\ 0 & \text{else} \end{cases}",
+                "cases(1 &x>0, 0 &\"else\")",
+            ),
+        ];
+        for (latex, typst) in cases {
+            assert_eq!(translate_math(latex).as_deref(), Ok(typst), "{}", latex);
+        }
+    }
+
+    #[test]
+    fn reports_math_it_cannot_translate() {
+        let cases = [
+            (r"\foo", "unsupported command `\\foo`"),
+            (r"\frac{a}{b", "unbalanced `{`"),
+            ("a}", "unbalanced `}`"),
+            (r"\end{x}", "`\\end{x}` without `\\begin`"),
+            (
+                r"\begin{tabular}x\end{tabular}",
+                "unsupported environment `tabular`",
+            ),
+        ];
+        for (latex, reason) in cases {
+            assert_eq!(translate_math(latex), Err(reason.to_string()), "{}", latex);
+        }
+
+        let (typst, warnings) =
+            Markdown("Try $\\foo$.\n").render_typst_with(&TypstOptions::default());
+        assert!(
+            typst.contains(r#"#raw(lang: "latex", "\\foo")"#),
+            "{}",
+            typst
+        );
+        assert_eq!(warnings.len(), 1, "{:?}", warnings);
+    }
+}
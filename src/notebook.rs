@@ -0,0 +1,228 @@
+use std::collections::HashMap;
+use std::sync::LazyLock;
+
+use pulldown_cmark_escape::escape_html;
+use regex::Regex;
+use serde::Deserialize;
+use serde_json::Value;
+
+use crate::markdown::strip_prolog;
+
+/// A Jupyter notebook, as saved in an `.ipynb` file (nbformat 4). Only the
+/// parts needed to display the notebook are read.
+#[derive(Deserialize)]
+struct Notebook {
+    cells: Vec<Cell>,
+    #[serde(default)]
+    metadata: Value,
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "cell_type", rename_all = "lowercase")]
+enum Cell {
+    Markdown {
+        source: Text,
+    },
+    Code {
+        source: Text,
+        #[serde(default)]
+        outputs: Vec<Output>,
+    },
+    Raw {
+        source: Text,
+    },
+}
+
+/// Output of a code cell, saved when the cell was last run
+#[derive(Deserialize)]
+#[serde(tag = "output_type", rename_all = "snake_case")]
+enum Output {
+    Stream {
+        text: Text,
+    },
+    ExecuteResult {
+        data: HashMap<String, Value>,
+    },
+    DisplayData {
+        data: HashMap<String, Value>,
+    },
+    Error {
+        ename: String,
+        evalue: String,
+        #[serde(default)]
+        traceback: Vec<String>,
+    },
+}
+
+/// Multi-line text, which notebooks store either as a single string or as
+/// a list of lines
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Text {
+    String(String),
+    Lines(Vec<String>),
+}
+
+impl Text {
+    fn join(&self) -> String {
+        match self {
+            Text::String(text) => text.clone(),
+            Text::Lines(lines) => lines.concat(),
+        }
+    }
+}
+
+/// The text of an output's data in a MIME type, if it has that type
+fn data_text(data: &HashMap<String, Value>, mime: &str) -> Option<String> {
+    match data.get(mime)? {
+        Value::String(text) => Some(text.clone()),
+        Value::Array(lines) => Some(lines.iter().filter_map(Value::as_str).collect()),
+        _ => None,
+    }
+}
+
+/// Convert a Jupyter notebook into a markdown note. Markdown cells are
+/// kept as they are, code cells become fenced code blocks, and the outputs
+/// saved with each code cell become html. The notebook is never run.
+pub(crate) fn to_markdown(json: &str) -> serde_json::Result<String> {
+    let notebook: Notebook = serde_json::from_str(json)?;
+    let language = ["/language_info/name", "/kernelspec/language"]
+        .iter()
+        .find_map(|pointer| notebook.metadata.pointer(pointer)?.as_str())
+        .unwrap_or("python");
+
+    let mut markdown = String::new();
+    for cell in &notebook.cells {
+        match cell {
+            Cell::Markdown { source } => push_block(&mut markdown, &source.join()),
+            Cell::Code { source, outputs } => {
+                let source = source.join();
+                // The fence must be longer than any run of backticks in the code
+                let longest = source.split(|c| c != '`').map(str::len).max().unwrap_or(0);
+                let fence = "`".repeat(longest.max(2) + 1);
+                push_block(
+                    &mut markdown,
+                    &format!("{}{}\n{}\n{}", fence, language, source.trim_end(), fence),
+                );
+                for output in outputs {
+                    push_block(&mut markdown, &output_html(output));
+                }
+            }
+            Cell::Raw { source } => push_block(&mut markdown, &preformatted(&source.join())),
+        }
+    }
+    Ok(markdown)
+}
+
+/// Append a block to markdown, separated from the last by a blank line
+fn push_block(markdown: &mut String, block: &str) {
+    let block = block.trim_end();
+    if block.is_empty() {
+        return;
+    }
+    if !markdown.is_empty() {
+        markdown.push('\n');
+    }
+    markdown.push_str(block);
+    markdown.push('\n');
+}
+
+/// Html for the output of a code cell, using the richest format that can
+/// be displayed: html, then SVG, PNG or JPEG images, then plain text.
+fn output_html(output: &Output) -> String {
+    match output {
+        Output::Stream { text } => preformatted(&text.join()),
+        Output::ExecuteResult { data } | Output::DisplayData { data } => {
+            if let Some(html) = data_text(data, "text/html") {
+                html_block(&html)
+            } else if let Some(svg) = data_text(data, "image/svg+xml") {
+                html_block(&strip_prolog(&svg))
+            } else if let Some((mime, image)) = ["image/png", "image/jpeg"]
+                .iter()
+                .find_map(|mime| Some((mime, data_text(data, mime)?)))
+            {
+                let base64: String = image.split_whitespace().collect();
+                html_block(&format!("<img src=\"data:{};base64,{}\">", mime, base64))
+            } else if let Some(text) = data_text(data, "text/plain") {
+                preformatted(&text)
+            } else {
+                String::new()
+            }
+        }
+        Output::Error {
+            ename,
+            evalue,
+            traceback,
+        } => {
+            let text = if traceback.is_empty() {
+                format!("{}: {}", ename, evalue)
+            } else {
+                traceback.join("\n")
+            };
+            // Tracebacks are coloured with ANSI escape codes
+            static ANSI: LazyLock<Regex> =
+                LazyLock::new(|| Regex::new(r"\x1b\[[0-9;]*[A-Za-z]").unwrap());
+            preformatted(&ANSI.replace_all(&text, ""))
+        }
+    }
+}
+
+/// An html block containing `html`. Blank lines would end the block in
+/// markdown, so they are removed, except inside `<pre>` and `<textarea>`
+/// where they are part of the text, and are written as `&#10;` instead.
+fn html_block(html: &str) -> String {
+    static PREFORMATTED: LazyLock<Regex> =
+        LazyLock::new(|| Regex::new(r"(?i)<(/?)(?:pre|textarea)\b").unwrap());
+    let mut block = String::from("<div class=\"output\">\n");
+    let mut depth: usize = 0;
+    for line in html.lines() {
+        if line.trim().is_empty() {
+            if depth > 0 {
+                block.push_str(line);
+                block.push_str("&#10;");
+            }
+            continue;
+        }
+        block.push_str(line);
+        block.push('\n');
+        for tag in PREFORMATTED.captures_iter(line) {
+            if tag[1].is_empty() {
+                depth += 1;
+            } else {
+                depth = depth.saturating_sub(1);
+            }
+        }
+    }
+    block.push_str("</div>");
+    block
+}
+
+/// A `<pre>` html block containing `text`
+fn preformatted(text: &str) -> String {
+    let mut html = String::from("<pre class=\"output\">");
+    escape_html(&mut html, text.trim_end()).unwrap();
+    html.push_str("</pre>");
+    html
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Markdown;
+
+    #[test]
+    fn keeps_blank_lines_inside_preformatted_html() {
+        let html = "<table>\n\n<tr><td>1</td></tr>\n</table>\n<PRE>a\n\n  \nb</PRE>\n\n\
+                    <textarea>c\n\nd</textarea>\n\n<p>e</p>";
+        let block = html_block(html);
+        assert_eq!(
+            block,
+            "<div class=\"output\">\n<table>\n<tr><td>1</td></tr>\n</table>\n<PRE>a\n\
+             &#10;  &#10;b</PRE>\n<textarea>c\n&#10;d</textarea>\n<p>e</p>\n</div>"
+        );
+
+        // The whole output stays a single html block
+        let (rendered, _) = Markdown(block.as_str()).render_html();
+        assert_eq!(rendered.into_string(), block);
+    }
+}
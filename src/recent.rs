@@ -0,0 +1,210 @@
+use std::fs::read_to_string;
+use std::path::Path;
+use std::process::Command;
+
+use chrono::{DateTime, Days, Local, NaiveDate, TimeZone};
+use maud::{html, Markup, DOCTYPE};
+
+use crate::{LabBook, Note, Theme};
+
+/// Number of lines of a diff shown for each note
+const DIFF_LENGTH: usize = 20;
+
+/// Time windows offered on the page, in days
+const WINDOWS: [(u64, &str); 4] = [
+    (0, "Today"),
+    (1, "Since yesterday"),
+    (7, "Last week"),
+    (30, "Last month"),
+];
+
+/// How a note changed within the time window
+enum Change {
+    /// Lines added and removed, and the start of the diff, from git
+    Diff {
+        added: usize,
+        removed: usize,
+        snippet: Vec<String>,
+        truncated: bool,
+    },
+    /// The note is new since the start of the window
+    New { lines: usize },
+    /// The note's file was modified, but its text is unchanged
+    Unchanged,
+    /// The notebook is not a git repository, so only the length of the
+    /// note is known
+    Unknown { lines: usize },
+}
+
+/// A note that changed, with when it was last modified and how
+type Changed = (Note<'static>, DateTime<Local>, Change);
+
+/// Run git in `dir`, returning its output if it succeeds
+fn git(dir: &Path, args: &[&str]) -> Option<String> {
+    let output = Command::new("git")
+        .arg("-C")
+        .arg(dir)
+        .args(args)
+        .output()
+        .ok()?;
+    if output.status.success() {
+        Some(String::from_utf8_lossy(&output.stdout).into_owned())
+    } else {
+        None
+    }
+}
+
+/// Work out how the file `name` in `dir` changed since the git commit
+/// `base`, including changes that are not yet committed. Without a commit,
+/// the file is compared with nothing.
+fn diff(dir: &Path, name: &str, base: Option<&str>) -> Change {
+    let lines = read_to_string(dir.join(name)).map_or(0, |text| text.lines().count());
+    let Some(base) = base
+        .filter(|base| git(dir, &["cat-file", "-e", &format!("{}:./{}", base, name)]).is_some())
+    else {
+        return Change::New { lines };
+    };
+    let Some(numstat) = git(dir, &["diff", "--numstat", base, "--", name]) else {
+        return Change::Unknown { lines };
+    };
+    let mut counts = numstat.split_whitespace().map(|n| n.parse().unwrap_or(0));
+    let (Some(added), Some(removed)) = (counts.next(), counts.next()) else {
+        return Change::Unchanged;
+    };
+
+    // Leave out the header, up to the first hunk
+    let patch = git(dir, &["diff", "--unified=1", base, "--", name]).unwrap_or_default();
+    let mut snippet: Vec<String> = patch
+        .lines()
+        .skip_while(|line| !line.starts_with("@@"))
+        .map(str::to_string)
+        .collect();
+    let truncated = snippet.len() > DIFF_LENGTH;
+    snippet.truncate(DIFF_LENGTH);
+    Change::Diff {
+        added,
+        removed,
+        snippet,
+        truncated,
+    }
+}
+
+impl LabBook {
+    /// Render a page listing the notes modified since the start of the day
+    /// `days` days ago, most recent first and grouped by day. If the
+    /// notebook is a git repository, each note shows the lines changed since
+    /// the last commit before the window, and the start of the diff.
+    pub fn render_recent(&self, days: u64, theme: &Theme) -> Markup {
+        let today = Local::now().date_naive();
+        let start = today
+            .checked_sub_days(Days::new(days))
+            .and_then(|date| date.and_hms_opt(0, 0, 0))
+            .and_then(|start| Local.from_local_datetime(&start).earliest())
+            .unwrap_or_default();
+        // Only the notes modified within the window are loaded
+        let notes: Vec<(Note<'static>, DateTime<Local>)> = self
+            .modified()
+            .into_iter()
+            .take_while(|(_, modified)| *modified >= start)
+            .filter_map(|(id, modified)| Some((self.note(id).ok()?, modified)))
+            .collect();
+
+        let repository = git(&self.dir, &["rev-parse", "--is-inside-work-tree"]).is_some();
+        let base = if repository {
+            let before = format!("--before={}", start.to_rfc3339());
+            git(&self.dir, &["rev-list", "-1", &before, "HEAD"])
+                .map(|commit| commit.trim().to_string())
+                .filter(|commit| !commit.is_empty())
+        } else {
+            None
+        };
+
+        let mut groups: Vec<(NaiveDate, Vec<Changed>)> = Vec::new();
+        for (note, modified) in notes {
+            let path = self.path(&note.id);
+            let name = path.file_name().unwrap_or_default().to_string_lossy();
+            let change = if repository {
+                diff(&self.dir, &name, base.as_deref())
+            } else {
+                let lines = read_to_string(&path).map_or(0, |text| text.lines().count());
+                Change::Unknown { lines }
+            };
+            let date = modified.date_naive();
+            match groups.last_mut() {
+                Some((day, changes)) if *day == date => changes.push((note, modified, change)),
+                _ => groups.push((date, vec![(note, modified, change)])),
+            }
+        }
+
+        html! {
+            (DOCTYPE)
+            head {
+                title { "Recent changes" }
+                (theme)
+                style {
+                    ".added { color: #859900; }"
+                    ".removed { color: #dc322f; }"
+                    ".hunk { color: #268bd2; }"
+                }
+            }
+            body {
+                h1 { "Recent changes" }
+                nav {
+                    @for (i, (window, label)) in WINDOWS.iter().enumerate() {
+                        @if i > 0 { " | " }
+                        @if *window == days {
+                            strong { (label) }
+                        } @else {
+                            a href={ "/recent?days=" (window) } { (label) }
+                        }
+                    }
+                }
+                @if groups.is_empty() {
+                    p { "No notes have changed since " (start.format("%A %-d %B")) "." }
+                }
+                @for (date, changes) in &groups {
+                    h2 { (date.format("%A %-d %B %Y")) }
+                    ul {
+                        @for (note, modified, change) in changes {
+                            li {
+                                (modified.format("%H:%M")) " "
+                                a href={ "/" (note.id) } { (note.title()) }
+                                " "
+                                @match change {
+                                    Change::Diff { added, removed, snippet, truncated } => {
+                                        span class="added" { "+" (added) }
+                                        " "
+                                        span class="removed" { "−" (removed) }
+                                        details {
+                                            summary { "Diff" }
+                                            pre {
+                                                @for line in snippet {
+                                                    @if line.starts_with('+') {
+                                                        span class="added" { (line) }
+                                                    } @else if line.starts_with('-') {
+                                                        span class="removed" { (line) }
+                                                    } @else if line.starts_with("@@") {
+                                                        span class="hunk" { (line) }
+                                                    } @else {
+                                                        (line)
+                                                    }
+                                                    "\n"
+                                                }
+                                                @if *truncated { "…\n" }
+                                            }
+                                        }
+                                    }
+                                    Change::New { lines } => {
+                                        "new, " span class="added" { "+" (lines) }
+                                    }
+                                    Change::Unchanged => { "(no changes to the text)" }
+                                    Change::Unknown { lines } => { "(" (lines) " lines)" }
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
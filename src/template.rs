@@ -0,0 +1,175 @@
+use std::collections::HashMap;
+use std::fs::read_to_string;
+use std::io;
+use std::path::Path;
+use std::sync::LazyLock;
+
+use regex::{Captures, Regex};
+
+use crate::{Error, Result};
+
+/// Template used for new notes when the notebook has no
+/// `templates/default.md` of its own
+const DEFAULT_TEMPLATE: &str = "---
+title: {{id}}
+date: {{date}}
+author: {{author}}
+---
+
+# {{id}}
+";
+
+/// Text of a new note, with `{{variable}}` placeholders to be filled in
+pub struct Template(String);
+
+impl Template {
+    /// Load the template `{dir}/templates/{name}.md`. If there is no
+    /// `default` template, a minimal built-in one is used instead.
+    pub fn load(dir: &Path, name: &str) -> Result<Template> {
+        let mut path = dir.join("templates").join(name);
+        path.set_extension("md");
+        match read_to_string(&path) {
+            Ok(text) => Ok(Template(text)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound && name == "default" => {
+                Ok(Template(DEFAULT_TEMPLATE.to_string()))
+            }
+            Err(e) => Err(Error::io(path, e)),
+        }
+    }
+
+    /// Replace each placeholder with the value of its variable. Placeholders
+    /// for unknown variables are left as they are. Values filled into the
+    /// yaml front matter are quoted where they would otherwise change its
+    /// meaning, e.g. an author of `Smith: J.`.
+    pub fn fill(&self, vars: &HashMap<&str, String>) -> String {
+        let end = front_matter_end(&self.0);
+        let mut filled = String::with_capacity(self.0.len());
+        for line in self.0[..end].split_inclusive('\n') {
+            filled.push_str(&fill_yaml_line(line, vars));
+        }
+        filled.push_str(&fill_text(&self.0[end..], vars, |value| value.to_string()));
+        filled
+    }
+}
+
+/// Byte offset of the end of the front matter at the start of the text,
+/// delimited by `---` and either `---` or `...` lines, or 0 if there is none
+fn front_matter_end(text: &str) -> usize {
+    let mut lines = text.split_inclusive('\n');
+    if lines.next().map(str::trim_end) != Some("---") {
+        return 0;
+    }
+    let mut end = text.find('\n').map_or(text.len(), |i| i + 1);
+    for line in lines {
+        end += line.len();
+        if matches!(line.trim_end(), "---" | "...") {
+            break;
+        }
+    }
+    end
+}
+
+/// Replace each placeholder with its value, as given by `value`.
+fn fill_text(text: &str, vars: &HashMap<&str, String>, value: impl Fn(&str) -> String) -> String {
+    static PLACEHOLDER: LazyLock<Regex> =
+        LazyLock::new(|| Regex::new(r"\{\{\s*([A-Za-z_]+)\s*\}\}").unwrap());
+    PLACEHOLDER
+        .replace_all(text, |caps: &Captures| match vars.get(&caps[1]) {
+            Some(v) => value(v),
+            None => caps[0].to_string(),
+        })
+        .into_owned()
+}
+
+/// Fill in a line of yaml. A `key: value` whose value is a quoted string
+/// has the values escaped for it, and a plain value that no longer reads
+/// back as the same text once filled in is written as a quoted string.
+fn fill_yaml_line(line: &str, vars: &HashMap<&str, String>) -> String {
+    static KEY: LazyLock<Regex> =
+        LazyLock::new(|| Regex::new(r"^(\s*[\w-]+:\s+)(.*?)(\s*)$").unwrap());
+    let Some(caps) = KEY.captures(line) else {
+        return fill_text(line, vars, |value| value.to_string());
+    };
+    let (key, value, end) = (&caps[1], &caps[2], &caps[3]);
+    let value = if value.starts_with('"') {
+        fill_text(value, vars, |v| {
+            let quoted = serde_json::to_string(v).unwrap();
+            quoted[1..quoted.len() - 1].to_string()
+        })
+    } else if value.starts_with('\'') {
+        fill_text(value, vars, |v| v.replace('\'', "''"))
+    } else {
+        let filled = fill_text(value, vars, |v| v.to_string());
+        if filled == value || is_plain(&filled) {
+            filled
+        } else {
+            serde_json::to_string(&filled).unwrap()
+        }
+    };
+    format!("{}{}{}", key, value, end)
+}
+
+/// Whether text reads as a yaml scalar with the same text, rather than as
+/// a mapping, a comment, a list or null.
+fn is_plain(text: &str) -> bool {
+    match serde_yaml::from_str::<serde_yaml::Value>(text) {
+        Ok(serde_yaml::Value::String(s)) => s == text,
+        Ok(serde_yaml::Value::Number(_) | serde_yaml::Value::Bool(_)) => true,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::NoteMetadata;
+
+    fn fill(template: &str, author: &str) -> String {
+        let vars = HashMap::from([
+            ("id", "run-3".to_string()),
+            ("date", "2024-01-31".to_string()),
+            ("time", "15:45".to_string()),
+            ("author", author.to_string()),
+            ("number", "3".to_string()),
+        ]);
+        Template(template.to_string()).fill(&vars)
+    }
+
+    #[test]
+    fn leaves_plain_values_unquoted() {
+        let note = fill(DEFAULT_TEMPLATE, "Ada Lovelace");
+        assert_eq!(
+            note,
+            "---\ntitle: run-3\ndate: 2024-01-31\nauthor: Ada Lovelace\n---\n\n# run-3\n"
+        );
+    }
+
+    #[test]
+    fn quotes_values_that_would_change_the_front_matter() {
+        for author in ["Smith: J.", "#lab", "null", "[x]", "O'Brien \"Bob\" \\"] {
+            let note = fill(DEFAULT_TEMPLATE, author);
+            let metadata = NoteMetadata::parse(&note).unwrap();
+            assert_eq!(metadata.author.as_deref(), Some(author), "{}", note);
+            assert_eq!(metadata.date.as_deref(), Some("2024-01-31"));
+        }
+    }
+
+    #[test]
+    fn escapes_values_inside_quoted_strings() {
+        let template = "---\ntitle: \"By {{author}}\"\nauthor: '{{author}}'\n\
+                        number: {{number}}\n---\nBy {{author}} at {{time}} {{unknown}}\n";
+        let note = fill(template, "O'Brien: \"Bob\"");
+        let metadata = NoteMetadata::parse(&note).unwrap();
+        assert_eq!(metadata.title.as_deref(), Some("By O'Brien: \"Bob\""));
+        assert_eq!(metadata.author.as_deref(), Some("O'Brien: \"Bob\""));
+        assert_eq!(metadata.number, Some(3));
+        assert!(note.ends_with("---\nBy O'Brien: \"Bob\" at 15:45 {{unknown}}\n"));
+    }
+
+    #[test]
+    fn quotes_the_whole_value_around_a_placeholder() {
+        let note = fill("---\ntitle: Notes by {{author}}\n---\n", "#lab");
+        let metadata = NoteMetadata::parse(&note).unwrap();
+        assert_eq!(metadata.title.as_deref(), Some("Notes by #lab"));
+    }
+}
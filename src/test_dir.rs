@@ -0,0 +1,42 @@
+//! Temporary directories of files for tests
+
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// A directory of files in the system's temporary directory, removed when
+/// dropped, even if the test fails
+pub(crate) struct TestDir(PathBuf);
+
+impl TestDir {
+    /// Create a new directory, unique to this test, holding files with the
+    /// given paths (relative to the directory) and contents
+    pub(crate) fn new(files: &[(&str, &str)]) -> TestDir {
+        static DIRS: AtomicUsize = AtomicUsize::new(0);
+        let dir = env::temp_dir().join(format!(
+            "labnotes-test-{}-{}",
+            process::id(),
+            DIRS.fetch_add(1, Ordering::Relaxed)
+        ));
+        let test_dir = TestDir(dir);
+        for (path, contents) in files {
+            let path = test_dir.0.join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, contents).unwrap();
+        }
+        fs::create_dir_all(&test_dir.0).unwrap();
+        test_dir
+    }
+
+    pub(crate) fn path(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TestDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}